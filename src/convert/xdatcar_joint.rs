@@ -1,33 +1,37 @@
 //! This module can joint two xdatcar into one xdatcar.
 //! 
-use std::error::Error;
 use std::fs::File;
 use std::fs;
 use std::io::Write;
 
-pub fn joint(input1: &str, input2: &Vec<&str>, output: &str) -> Result<(), Box<dyn Error>> {
+use crate::error::Error;
+
+pub fn joint(input1: &str, input2: &Vec<&str>, output: &str) -> Result<(), Error> {
     
     let contents1 = fs::read_to_string(input1)?;
-    if input2.len() != 2 {panic!("taskopt for convert xdatcar_joint should be like \"xdatcar_joint ./some.xdatcar\"")}
+    if input2.len() != 2 {
+        return Err(Error::InvalidOption(
+            "taskopt for convert xdatcar_joint should be like \"xdatcar_joint ./some.xdatcar\"".to_string()));
+    }
     let contents2 = fs::read_to_string(input2[1])?;
-    let mut o = fs::File::create(&output).unwrap();
+    let mut o = fs::File::create(&output)?;
     let mut frame_idx: i32 = 1;
 
     for (i, line) in contents1.lines().enumerate() {
-        if line.chars().next().unwrap() == 'D'{
-            o.write( (format!("Direct  configuration= {}", frame_idx) +"\n").as_bytes() ).expect("Write failed!");
+        if line.starts_with('D'){
+            o.write_all( (format!("Direct  configuration= {}", frame_idx) +"\n").as_bytes() )?;
             frame_idx += 1;
         } else {
-            o.write( (line.to_owned()+"\n").as_bytes() ).expect("Write failed!");
+            o.write_all( (line.to_owned()+"\n").as_bytes() )?;
         }
     }
 
     for (i, line) in contents2.lines().enumerate() {
-        if line.chars().next().unwrap() == 'D' && i > 6 {
-            o.write( (format!("Direct  configuration= {}", frame_idx)+"\n").as_bytes()).expect("Write failed!");
+        if line.starts_with('D') && i > 6 {
+            o.write_all( (format!("Direct  configuration= {}", frame_idx)+"\n").as_bytes())?;
             frame_idx += 1;
         } else if i > 6 {
-            o.write((line.to_owned()+"\n").as_bytes()).expect("Write failed!");
+            o.write_all((line.to_owned()+"\n").as_bytes())?;
         }
     }
 
@@ -0,0 +1,64 @@
+//! The error type of this program.
+//!
+//! Loaders and tasks return Error instead of panicking, so that a bad
+//! input file or option ends the program with a message and exit code 1.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+#[derive(Debug)]
+pub enum Error {
+    /// a value in the input file can't be parsed,
+    /// line and column (the n-th word of the line) start from 1
+    Parse { file: String, line: usize, column: usize, message: String },
+    /// the input file does not look like the given format
+    Format(String),
+    /// the molecules are not as expected, e.g. an O without two H
+    Topology(String),
+    /// can't read or write a file
+    Io(io::Error),
+    /// wrong --infmt, --task, --taskopt, --frameopt, etc.
+    InvalidOption(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse { file, line, column, message } => write!(
+                f,
+                "can't parse {}, line {}, column {}: {}",
+                file, line, column, message
+            ),
+            Error::Format(message) => write!(f, "wrong file format: {}", message),
+            Error::Topology(message) => write!(f, "unexpected topology: {}", message),
+            Error::Io(e) => write!(f, "{}", e),
+            Error::InvalidOption(message) => write!(f, "invalid option: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+/// Parse the i-th option (from 0) of opt, `name` is used in the message.
+///
+/// e.g. parse_opt::<f64>(&rdfopt, 2, "rcut")
+pub fn parse_opt<T: FromStr>(opt: &Vec<&str>, i: usize, name: &str) -> Result<T, Error> {
+    match opt.get(i) {
+        Some(value) => value.parse::<T>().map_err(|_| {
+            Error::InvalidOption(format!("can't parse {} from \"{}\"", name, value))
+        }),
+        None => Err(Error::InvalidOption(format!(
+            "{} is missing, {} option(s) are given: {:?}",
+            name,
+            opt.len(),
+            opt
+        ))),
+    }
+}
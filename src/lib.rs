@@ -1,15 +1,18 @@
 /// The main lib for the water_analysis program
 /// The main run() function is defined in this file.
 //  use std::env;
-use std::error::Error;
 //  use std::fs;
 //use cgmath::Vector3;
 use std::fmt;
 
+pub mod error;
 pub mod load;
 pub mod task;
 pub mod convert;
 
+pub use error::Error;
+use error::parse_opt;
+
 extern crate clap;
 use clap::{Arg, ArgMatches, App, SubCommand};
 
@@ -25,20 +28,43 @@ pub struct Config {
 }*/
 
 ///  save the information of an atom
+///
+///  Only index, type_name and coordination are always there,
+///  the others are filled when the input file provides them.
+#[derive(Default)]
 pub struct Atom {
     pub index       : i32,
     pub type_name   : String,
     pub coordination : [f64; 3],
+    pub image       : Option<[i32; 3]>,
+    pub velocity    : Option<[f64; 3]>,
+    pub force       : Option<[f64; 3]>,
+    pub charge      : Option<f64>,
+}
+
+///  save the lattice of one frame
+///
+///  `matrix` holds the lattice vectors a, b, c as rows (in Angstrom),
+///  `inverse` is kept alongside so that Cartesian -> fractional is cheap.
+///  `origin` is the lower corner of the cell (only lammps has a non-zero one).
+#[derive(Clone)]
+pub struct Cell {
+    pub matrix       : [[f64; 3]; 3],
+    pub inverse      : [[f64; 3]; 3],
+    pub origin       : [f64; 3],
 }
 
 ///  save the information of one frame
 pub struct Frame {
     pub frame_idx    : i32,
-    pub cell         : Vec<f64>,
+    pub cell         : Cell,
     pub atom_type    : Vec<String>,
     pub atom_numb    : Vec<i32>,
     pub natom        : i32,
     pub atom         : Vec<Atom>,
+    /// the velocities are computed from the positions (see task::velocity),
+    /// not read from the input file
+    pub velocity_derived : bool,
 }
 /*
 impl Config {
@@ -130,6 +156,142 @@ impl fmt::Display for Atom {
     }
 }
 
+impl Cell {
+    /// Build a cell from the three lattice vectors (rows of `matrix`).
+    pub fn new(matrix: [[f64; 3]; 3]) -> Cell {
+        let m = &matrix;
+        let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+                - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+                + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+        let mut inverse = [[0.0; 3]; 3];
+        for i in 0..3 {
+            for j in 0..3 {
+                //  cofactor of m[j][i], i.e. the adjugate transposed
+                let (j1, j2) = ((j + 1) % 3, (j + 2) % 3);
+                let (i1, i2) = ((i + 1) % 3, (i + 2) % 3);
+                inverse[i][j] = (m[j1][i1] * m[j2][i2] - m[j1][i2] * m[j2][i1]) / det;
+            }
+        }
+        Cell { matrix, inverse, origin: [0.0; 3] }
+    }
+
+    /// Build an orthogonal cell from the box lengths [Lx, Ly, Lz].
+    pub fn from_lengths(lengths: [f64; 3]) -> Cell {
+        Cell::new([
+            [lengths[0], 0.0, 0.0],
+            [0.0, lengths[1], 0.0],
+            [0.0, 0.0, lengths[2]],
+        ])
+    }
+
+    /// Lengths of the three lattice vectors |a|, |b|, |c|.
+    pub fn lengths(&self) -> [f64; 3] {
+        let mut l = [0.0; 3];
+        for i in 0..3 {
+            l[i] = (self.matrix[i][0].powi(2) + self.matrix[i][1].powi(2)
+                    + self.matrix[i][2].powi(2)).sqrt();
+        }
+        l
+    }
+
+    /// Volume of the cell, a . (b x c).
+    pub fn volume(&self) -> f64 {
+        let m = &self.matrix;
+        (m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])).abs()
+    }
+
+    /// True if all off-diagonal components are zero.
+    pub fn is_orthogonal(&self) -> bool {
+        (0..3).all(|i| (0..3).all(|j| i == j || self.matrix[i][j] == 0.0))
+    }
+
+    /// Convert fractional coordinates to Cartesian, r = s . M
+    pub fn to_cartesian(&self, frac: [f64; 3]) -> [f64; 3] {
+        let mut r = [0.0; 3];
+        for j in 0..3 {
+            r[j] = frac[0] * self.matrix[0][j] + frac[1] * self.matrix[1][j] + frac[2] * self.matrix[2][j];
+        }
+        r
+    }
+
+    /// Convert Cartesian coordinates to fractional, s = r . M^-1
+    pub fn to_fractional(&self, cart: [f64; 3]) -> [f64; 3] {
+        let mut s = [0.0; 3];
+        for j in 0..3 {
+            s[j] = cart[0] * self.inverse[0][j] + cart[1] * self.inverse[1][j] + cart[2] * self.inverse[2][j];
+        }
+        s
+    }
+
+    /// Put a Cartesian position back into the cell, i.e. fractional in [0, 1).
+    pub fn wrap(&self, cart: [f64; 3]) -> [f64; 3] {
+        self.wrap_with_image(cart).0
+    }
+
+    /// Same as wrap(), but also return the image (number of cells) the
+    /// position was shifted from, i.e. cart = wrapped + image . M
+    pub fn wrap_with_image(&self, cart: [f64; 3]) -> ([f64; 3], [i32; 3]) {
+        let mut s = self.to_fractional([
+            cart[0] - self.origin[0],
+            cart[1] - self.origin[1],
+            cart[2] - self.origin[2],
+        ]);
+        let mut image = [0; 3];
+        for i in 0..3 {
+            image[i] = s[i].floor() as i32;
+            s[i] -= s[i].floor();
+        }
+        let mut r = self.to_cartesian(s);
+        for i in 0..3 {
+            r[i] += self.origin[i];
+        }
+        (r, image)
+    }
+
+    /// Return the minimum image of a Cartesian displacement.
+    ///
+    /// The displacement is first reduced in fractional coordinates; for
+    /// skewed cells this is not always the shortest vector, so the 27
+    /// neighbouring images are checked as well.
+    pub fn minimum_image(&self, delta: [f64; 3]) -> [f64; 3] {
+        let mut s = self.to_fractional(delta);
+        for i in 0..3 {
+            s[i] -= s[i].round();
+        }
+        let reduced = self.to_cartesian(s);
+        if self.is_orthogonal() {
+            return reduced;
+        }
+        let mut best = reduced;
+        let mut best_d2 = reduced.iter().map(|x| x * x).sum::<f64>();
+        for i in -1..=1 {
+            for j in -1..=1 {
+                for k in -1..=1 {
+                    if i == 0 && j == 0 && k == 0 {
+                        continue;
+                    }
+                    let shift = self.to_cartesian([i as f64, j as f64, k as f64]);
+                    let trial = [reduced[0] + shift[0], reduced[1] + shift[1], reduced[2] + shift[2]];
+                    let d2 = trial.iter().map(|x| x * x).sum::<f64>();
+                    if d2 < best_d2 {
+                        best = trial;
+                        best_d2 = d2;
+                    }
+                }
+            }
+        }
+        best
+    }
+}
+
+impl fmt::Debug for Cell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.matrix)
+    }
+}
+
 impl fmt::Debug for Frame {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -144,7 +306,7 @@ impl fmt::Debug for Frame {
 /// contain the information of parameter, file to be processed
 /// and what will be done.
 /// And run the process.
-pub fn run(config: ArgMatches) -> Result<(), Box<dyn Error>> {
+pub fn run(config: ArgMatches) -> Result<(), Error> {
     println!("input file: {}", config.value_of("inputfile").unwrap());
     println!("filetype: {}", config.value_of("input fmt").unwrap());
 
@@ -152,91 +314,199 @@ pub fn run(config: ArgMatches) -> Result<(), Box<dyn Error>> {
 
     println!("task    : {}", task);
 
+    //  the frames of rdf, cov, hb and q are computed in parallel
+    if let Some(threads) = config.value_of("threads") {
+        let threads: usize = parse_opt(&vec![threads], 0, "threads")?;
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .map_err(|e| Error::InvalidOption(format!("can't use {} threads: {}", threads, e)))?;
+    }
+
 
 
     // load input file
     
     if task != "convert" {
-        let mut frameopt: Vec<&str> = config.value_of("frameopt").unwrap()
+        //  all the frames if frameopt is not given
+        let frameopt: Vec<&str> = config.value_of("frameopt").unwrap_or("1 2147483647 1")
                           .split_whitespace().collect();
-        let mut system: Vec<Frame>;
-        match &config.value_of("input fmt").unwrap() as &str {
-            "vasp/poscar" => {
-                println!("Loading input files, please wait...");
-                system = load::vasp::read_poscar(config.value_of("inputfile").unwrap())?;
-            }
-            "vasp/xdatcar" => {
-                println!("Loading input files, please wait...");
-                system = load::vasp::read_xdatcar(config.value_of("inputfile").unwrap(), &frameopt)?;
-            }
-            "qe/traj" => {
-                println!("Loading input files, please wait...");
-                system = load::qe::read_traj(config.value_of("inputfile").unwrap(), &frameopt)?;
-            }
-            "lammps/traj" => {
-                println!("Loading input files, please wait...");
-                system = load::lammps::read_lammpstrj(config.value_of("inputfile").unwrap(), &frameopt)?;
-            }
-            _ => panic!("can't read filetype, please check your filetype!"),
-        };
-        //println!("{:?}", system);
-        println!("Total {:?} frames was loaded.", system.len());
+        let typemap: Vec<&str> = config.value_of("typemap").unwrap_or("")
+                          .split_whitespace().collect();
+        //  frames are read one at a time while the task is running
+        let system = load::open(config.value_of("inputfile").unwrap(),
+                                config.value_of("input fmt").unwrap(),
+                                &frameopt, &typemap)?;
         match task as &str {
             "rdf" => {
-                let mut taskopt: Vec<&str> = config.value_of("taskopt").unwrap()
+                let mut taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
                   .split_whitespace().collect();
                 println!("task option: {:?}", taskopt);
                 println!("Running task, please wait...");
-                task::rdf::rdf(&mut system, &taskopt, &config.value_of("outputfile").unwrap())?;
+                task::rdf::rdf(system, &taskopt, &config.value_of("outputfile").unwrap())?;
             }
             "cov" => {
+                let taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
+                  .split_whitespace().collect();
+                println!("task option: {:?}", taskopt);
                 println!("Running task, please wait...");
-                task::cov::cov(&mut system, config.value_of("outputfile").unwrap())?;
+                task::cov::cov(system, &taskopt, config.value_of("outputfile").unwrap())?;
             }
             "hb" => {
+                let taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
+                  .split_whitespace().collect();
+                println!("task option: {:?}", taskopt);
+                println!("Running task, please wait...");
+                task::hb::compute_HBs(system, &taskopt, config.value_of("outputfile").unwrap())?;
+            }
+            "hbpop" => {
+                let taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
+                  .split_whitespace().collect();
+                println!("task option: {:?}", taskopt);
+                println!("Running task, please wait...");
+                task::hb::hb_population(system, &taskopt, config.value_of("outputfile").unwrap())?;
+            }
+            "hblife" => {
+                let taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
+                  .split_whitespace().collect();
+                println!("task option: {:?}", taskopt);
                 println!("Running task, please wait...");
-                task::hb::compute_HBs(&mut system, config.value_of("outputfile").unwrap())?;
+                task::hblife::hb_lifetime(system, &taskopt, config.value_of("outputfile").unwrap())?;
             }
-            "q" => {
+            "species" => {
+                println!("Running task, please wait...");
+                task::topology::species(system, config.value_of("outputfile").unwrap())?;
+            }
+            "q" | "sk" | "translational" => {
+                let taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
+                  .split_whitespace().collect();
+                println!("task option: {:?}", taskopt);
+                println!("Running task, please wait...");
+                if task == "q" {
+                    task::q::q(system, &taskopt, config.value_of("outputfile").unwrap())?;
+                } else if task == "sk" {
+                    task::q::sk(system, &taskopt, config.value_of("outputfile").unwrap())?;
+                } else {
+                    task::q::translational(system, &taskopt, config.value_of("outputfile").unwrap())?;
+                }
+            }
+            "lsi" | "zeta" => {
+                let taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
+                  .split_whitespace().collect();
+                println!("task option: {:?}", taskopt);
+                println!("Running task, please wait...");
+                if task == "lsi" {
+                    task::lsi::lsi(system, &taskopt, config.value_of("outputfile").unwrap())?;
+                } else {
+                    task::lsi::zeta(system, &taskopt, config.value_of("outputfile").unwrap())?;
+                }
+            }
+            "steinhardt" => {
+                let taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
+                  .split_whitespace().collect();
+                println!("task option: {:?}", taskopt);
                 println!("Running task, please wait...");
-                task::q::q(&mut system, config.value_of("outputfile").unwrap())?;
+                task::steinhardt::steinhardt(system, &taskopt, config.value_of("outputfile").unwrap())?;
             }
             "msd" => {
-                let mut taskopt: Vec<&str> = config.value_of("taskopt").unwrap()
+                let mut taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
                   .split_whitespace().collect();
                 println!("task option: {:?}", taskopt);
+                //  msd needs all the frames at the same time
+                println!("Loading input files, please wait...");
+                let mut system: Vec<Frame> = system.collect::<Result<Vec<Frame>, _>>()?;
+                println!("Total {:?} frames was loaded.", system.len());
                 println!("Running task, please wait...");
                 task::msd::msd(&mut system, &taskopt, config.value_of("outputfile").unwrap())?;
             }
+            "ngp" | "vanhove" => {
+                let taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
+                  .split_whitespace().collect();
+                println!("task option: {:?}", taskopt);
+                //  the displacements need all the frames at the same time
+                println!("Loading input files, please wait...");
+                let mut system: Vec<Frame> = system.collect::<Result<Vec<Frame>, _>>()?;
+                println!("Total {:?} frames was loaded.", system.len());
+                println!("Running task, please wait...");
+                if task == "ngp" {
+                    task::vanhove::ngp(&mut system, &taskopt, config.value_of("outputfile").unwrap())?;
+                } else {
+                    task::vanhove::van_hove(&mut system, &taskopt, config.value_of("outputfile").unwrap())?;
+                }
+            }
+            "unwrap" => {
+                let taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
+                  .split_whitespace().collect();
+                println!("task option: {:?}", taskopt);
+                //  unwrapping needs all the frames at the same time
+                println!("Loading input files, please wait...");
+                let mut system: Vec<Frame> = system.collect::<Result<Vec<Frame>, _>>()?;
+                println!("Total {:?} frames was loaded.", system.len());
+                println!("Running task, please wait...");
+                task::unwrap::write_unwrapped(&mut system, &taskopt, config.value_of("outputfile").unwrap())?;
+            }
+            "vacf" => {
+                let taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
+                  .split_whitespace().collect();
+                println!("task option: {:?}", taskopt);
+                //  the correlation needs all the frames at the same time
+                println!("Loading input files, please wait...");
+                let mut system: Vec<Frame> = system.collect::<Result<Vec<Frame>, _>>()?;
+                println!("Total {:?} frames was loaded.", system.len());
+                println!("Running task, please wait...");
+                task::vacf::vacf(&mut system, &taskopt, config.value_of("outputfile").unwrap())?;
+            }
+            "temperature" => {
+                let taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
+                  .split_whitespace().collect();
+                println!("task option: {:?}", taskopt);
+                //  the velocities may be derived from the neighbouring frames
+                println!("Loading input files, please wait...");
+                let mut system: Vec<Frame> = system.collect::<Result<Vec<Frame>, _>>()?;
+                println!("Total {:?} frames was loaded.", system.len());
+                println!("Running task, please wait...");
+                task::velocity::temperature(&mut system, &taskopt, config.value_of("outputfile").unwrap())?;
+            }
+            "density" | "slab" => {
+                let taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
+                  .split_whitespace().collect();
+                println!("task option: {:?}", taskopt);
+                println!("Running task, please wait...");
+                if task == "density" {
+                    task::density::density(system, &taskopt, config.value_of("outputfile").unwrap())?;
+                } else {
+                    task::density::slab(system, &taskopt, config.value_of("outputfile").unwrap())?;
+                }
+            }
             "dist" => {
-                let mut taskopt: Vec<&str> = config.value_of("taskopt").unwrap()
+                let mut taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
                   .split_whitespace().collect();
                 println!("task option: {:?}", taskopt);
                 println!("Running task, please wait...");
-                task::distance::compute_distance(&mut system, &taskopt, config.value_of("outputfile").unwrap())?;
+                task::distance::compute_distance(system, &taskopt, config.value_of("outputfile").unwrap())?;
             }
-            _ => panic!("unknown task, please check your task!"),
+            _ => return Err(Error::InvalidOption(format!("unknown task {}, please check your task!", task))),
         };
     } else {
-        let mut taskopt: Vec<&str> = config.value_of("taskopt").unwrap()
+        let mut taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
                   .split_whitespace().collect();
 
-        match taskopt[0] as &str {
+        match taskopt.get(0).cloned().unwrap_or("") {
             "qe2xdatcar" => {
-                let mut taskopt: Vec<&str> = config.value_of("taskopt").unwrap()
+                let mut taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
                   .split_whitespace().collect();
                 println!("task option: {:?}", taskopt);
                 println!("Running task, please wait...");
                 convert::qe2xdatcar::qe2xdatcar(config.value_of("inputfile").unwrap(), &config.value_of("outputfile").unwrap())?;
             }
             "xdatcar_joint" => {
-                let mut taskopt: Vec<&str> = config.value_of("taskopt").unwrap()
+                let mut taskopt: Vec<&str> = config.value_of("taskopt").unwrap_or("")
                   .split_whitespace().collect();
                 println!("task option: {:?}", taskopt);
                 println!("Running task, please wait...");
                 convert::xdatcar_joint::joint(config.value_of("inputfile").unwrap(), &taskopt, &config.value_of("outputfile").unwrap())?;
             }
-            _ => panic!("unknown convert option, please check!"),
+            _ => return Err(Error::InvalidOption("unknown convert option, please check!".to_string())),
         }
     }
      
@@ -1,108 +1,329 @@
 //! This module contains functions that read files in lammps format,
 //! including lammpstrj.
+//!
+//! The columns of the dump are taken from the `ITEM: ATOMS` line, the
+//! following keywords are understood (others are ignored):
+//!
+//! ```text
+//! id type element x y z xs ys zs xu yu zu ix iy iz vx vy vz fx fy fz q
+//! ```
+//!
+//! Lammps only writes numeric types, so a typemap like `"O H"` (type 1 is O,
+//! type 2 is H) can be given to get the element names used by the tasks.
 
 
-use std::error::Error;
-use std::fs;
-use crate::{Atom, Frame};
+use crate::error::Error;
+use crate::load::{FrameReader, Frames, LineReader};
+use crate::{Atom, Cell, Frame};
 
-/// read from lammpstrj and return vec![Frame1, Frame2, ...]
-/// For now, this function can only load for nvt.
-pub fn read_lammpstrj(filename: &str, frameopt: &Vec<&str>) -> Result<Vec<Frame>, Box<dyn Error>> {
-    //  read from POSCAR and return vec![Frame]
-
-    //  ------Collect the basic information of the frame---------
-    //  Every frame has 9+natom lines.
-    //  ITEM: TIMESTEP
-    //  ....
-    //  1 1 x y z
-    //  ITEM: TIMESTEP ...
-    let contents = fs::read_to_string(filename)?;
-    let mut lines = vec![];
-    for line in contents.lines() {
-        lines.push(line);
+/// read the box from the four lines starting with `ITEM: BOX BOUNDS`.
+///
+/// Both orthogonal boxes (`xlo xhi`) and triclinic boxes
+/// (`ITEM: BOX BOUNDS xy xz yz ...`, `xlo_bound xhi_bound xy`) are supported.
+/// The lattice vectors are a = (lx, 0, 0), b = (xy, ly, 0), c = (xz, yz, lz).
+pub fn read_box(lines: &mut LineReader) -> Result<Cell, Error> {
+    if !lines.expect_line()?.starts_with("ITEM: BOX BOUNDS") {
+        return Err(lines.format_error("expect \"ITEM: BOX BOUNDS\""));
+    }
+    let triclinic: bool = lines.line.split_whitespace().any(|x| x == "xy");
+    let mut bounds = [[0.0; 3]; 3];
+    for i in 0..3 {
+        lines.expect_line()?;
+        bounds[i][0] = lines.parse(1)?;
+        bounds[i][1] = lines.parse(2)?;
+        if triclinic {
+            bounds[i][2] = lines.parse(3)?;
+        }
+    }
+    let (xy, xz, yz) = (bounds[0][2], bounds[1][2], bounds[2][2]);
+    //  the bounds written by lammps enclose the tilted box, see `dump` doc
+    let xlo = bounds[0][0] - [0.0, xy, xz, xy + xz].iter().cloned().fold(f64::MAX, f64::min);
+    let xhi = bounds[0][1] - [0.0, xy, xz, xy + xz].iter().cloned().fold(f64::MIN, f64::max);
+    let ylo = bounds[1][0] - 0.0_f64.min(yz);
+    let yhi = bounds[1][1] - 0.0_f64.max(yz);
+    let (zlo, zhi) = (bounds[2][0], bounds[2][1]);
+    let mut cell = Cell::new([
+        [xhi - xlo, 0.0, 0.0],
+        [xy, yhi - ylo, 0.0],
+        [xz, yz, zhi - zlo],
+    ]);
+    cell.origin = [xlo, ylo, zlo];
+    Ok(cell)
+}
+
+/// column (from 1) of each keyword in the `ITEM: ATOMS` line, None if absent.
+fn find_column(columns: &Vec<&str>, keys: [&str; 3]) -> Option<[usize; 3]> {
+    let mut idx = [0; 3];
+    for i in 0..3 {
+        idx[i] = columns.iter().position(|x| x == &keys[i])? + 1;
+    }
+    Some(idx)
+}
+
+/// read three columns of the current line as [f64; 3].
+fn read_column3(lines: &LineReader, idx: [usize; 3]) -> Result<[f64; 3], Error> {
+    Ok([lines.parse(idx[0])?, lines.parse(idx[1])?, lines.parse(idx[2])?])
+}
+
+/// Parse the atoms of one frame.
+///
+/// `header` is the `ITEM: ATOMS ...` line, the natom lines below it are read
+/// from `lines`. The atoms are sorted by id.
+pub fn read_atoms(
+    header: &str,
+    lines: &mut LineReader,
+    natom: usize,
+    cell: &Cell,
+    typemap: &Vec<String>,
+) -> Result<Vec<Atom>, Error> {
+    //  "ITEM: ATOMS id type x y z" -> ["id", "type", "x", "y", "z"]
+    let columns: Vec<&str> = header.split_whitespace().skip(2).collect();
+    let column = |key: &str| columns.iter().position(|x| x == &key).map(|x| x + 1);
+    let col_id = column("id");
+    let col_type = column("type");
+    let col_element = column("element");
+    let col_q = column("q");
+    let col_x = find_column(&columns, ["x", "y", "z"]);
+    let col_xs = find_column(&columns, ["xs", "ys", "zs"]);
+    let col_xu = find_column(&columns, ["xu", "yu", "zu"]);
+    let col_ix = find_column(&columns, ["ix", "iy", "iz"]);
+    let col_v = find_column(&columns, ["vx", "vy", "vz"]);
+    let col_f = find_column(&columns, ["fx", "fy", "fz"]);
+    if col_x.is_none() && col_xs.is_none() && col_xu.is_none() {
+        return Err(lines.format_error("no coordination (x y z, xs ys zs or xu yu zu) in ITEM: ATOMS"));
+    }
+    if col_element.is_none() && col_type.is_none() {
+        return Err(lines.format_error("no type or element in ITEM: ATOMS"));
     }
-    let natom: i32 = lines[3].parse::<i32>().unwrap();
-    let lx: f64 = lines[5].split_whitespace()
-                    .collect::<Vec<&str>>()[1].parse::<f64>().unwrap();
-    let ly: f64 = lines[6].split_whitespace()
-                    .collect::<Vec<&str>>()[1].parse::<f64>().unwrap();
-    let lz: f64 = lines[7].split_whitespace()
-                    .collect::<Vec<&str>>()[1].parse::<f64>().unwrap();
-    let cell: Vec<f64> = vec![lx, ly, lz];
-    let mut atom_type: Vec<String> = vec![];
-    let mut atom_numb: Vec<i32> = vec![];
-
-    //  --------Get atom_type and atom_numb from frame 1------
-    // println!("Getting atom_type and atom_numb from frame 1.");
-    let mut i: i32 = 9;
-    while lines[i as usize] != "ITEM: TIMESTEP" {
-        let line = lines[i as usize].split_whitespace().collect::<Vec<&str>>();
-        if atom_type.contains(&line[1].to_string()) {
-            for (i, tmp_type) in atom_type.iter().enumerate() {
-                if &line[1] == tmp_type {
-                    atom_numb[i] += 1;
+
+    let mut atoms: Vec<Atom> = vec![];
+    for j in 0..natom {
+        lines.expect_line()?;
+        let index: i32 = match col_id {
+            Some(c) => lines.parse(c)?,
+            None => j as i32 + 1,
+        };
+        let type_name: String = match (col_element, col_type) {
+            (Some(c), _) => lines.parse(c)?,
+            (_, Some(c)) => {
+                let t: usize = lines.parse(c)?;
+                if t >= 1 && t <= typemap.len() {
+                    typemap[t - 1].to_string()
+                } else {
+                    t.to_string()
                 }
             }
+            (None, None) => unreachable!(),
+        };
+        let mut image: Option<[i32; 3]> = match col_ix {
+            Some(c) => Some([lines.parse(c[0])?, lines.parse(c[1])?, lines.parse(c[2])?]),
+            None => None,
+        };
+        //  wrapped position is preferred, then scaled, then unwrapped
+        let coordination: [f64; 3] = if let Some(c) = col_x {
+            read_column3(lines, c)?
+        } else if let Some(c) = col_xs {
+            let r = cell.to_cartesian(read_column3(lines, c)?);
+            [r[0] + cell.origin[0], r[1] + cell.origin[1], r[2] + cell.origin[2]]
         } else {
-            atom_type.push(line[1].to_string());
-            atom_numb.push(1)
+            //  keep the image so that the unwrapped position is not lost
+            let (r, ix) = cell.wrap_with_image(read_column3(lines, col_xu.unwrap())?);
+            if image.is_none() {
+                image = Some(ix);
+            }
+            r
+        };
+        let velocity = match col_v {
+            Some(c) => Some(read_column3(lines, c)?),
+            None => None,
+        };
+        let force = match col_f {
+            Some(c) => Some(read_column3(lines, c)?),
+            None => None,
+        };
+        let charge = match col_q {
+            Some(c) => Some(lines.parse(c)?),
+            None => None,
+        };
+        atoms.push(Atom {
+            index,
+            type_name,
+            coordination,
+            image,
+            velocity,
+            force,
+            charge,
+        });
+    }
+    atoms.sort_by_key(|x| x.index);
+    Ok(atoms)
+}
+
+/// Read lammpstrj frame by frame.
+///
+/// The box and the number of atoms are read for every frame,
+/// so npt and grand canonical trajectory are fine.
+///
+/// ```text
+/// ITEM: TIMESTEP
+/// 0
+/// ITEM: NUMBER OF ATOMS
+/// natom
+/// ITEM: BOX BOUNDS pp pp pp
+/// xlo xhi
+/// ylo yhi
+/// zlo zhi
+/// ITEM: ATOMS id type x y z
+/// 1 1 x y z
+/// ITEM: TIMESTEP ...
+/// ```
+pub struct LammpsReader {
+    lines     : LineReader,
+    typemap   : Vec<String>,
+    frame_idx : i32,
+}
+
+impl LammpsReader {
+    /// typemap in the form of vec!["element of type 1", "element of type 2", ...].
+    pub fn open(filename: &str, typemap: &Vec<&str>) -> Result<LammpsReader, Error> {
+        Ok(LammpsReader {
+            lines: LineReader::open(filename)?,
+            typemap: typemap.iter().map(|x| x.to_string()).collect(),
+            frame_idx: 0,
+        })
+    }
+
+    /// Read the first 9 lines of a frame, return (natom, cell, atoms header)
+    /// or None at the end of file.
+    fn read_head(&mut self) -> Result<Option<(usize, Cell, String)>, Error> {
+        if !self.lines.next_line()? || self.lines.line.trim().is_empty() {
+            return Ok(None);
+        }
+        if self.lines.line.trim() != "ITEM: TIMESTEP" {
+            return Err(self.lines.format_error("expect \"ITEM: TIMESTEP\""));
+        }
+        self.lines.expect_line()?;
+        self.lines.expect_line()?;
+        self.lines.expect_line()?;
+        let natom: usize = self.lines.parse(1)?;
+        //  `ITEM: BOX BOUNDS` and the 3 lines below it
+        let cell: Cell = read_box(&mut self.lines)?;
+        let header: String = self.lines.expect_line()?.to_string();
+        if !header.starts_with("ITEM: ATOMS") {
+            return Err(self.lines.format_error("expect \"ITEM: ATOMS\""));
         }
-        i += 1;
+        self.frame_idx += 1;
+        Ok(Some((natom, cell, header)))
     }
-    
-    if atom_numb.iter().sum::<i32>() != natom {
-        panic!("Atom number of frame 1 is not equal to natom!");
+}
+
+impl FrameReader for LammpsReader {
+    fn read_frame(&mut self) -> Result<Option<Frame>, Error> {
+        let (natom, cell, header) = match self.read_head()? {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        let atom: Vec<Atom> = read_atoms(&header, &mut self.lines, natom, &cell, &self.typemap)?;
+
+        //  atom_type in the order of first appearance
+        let mut atom_type: Vec<String> = vec![];
+        let mut atom_numb: Vec<i32> = vec![];
+        for a in atom.iter() {
+            match atom_type.iter().position(|x| x == &a.type_name) {
+                Some(k) => atom_numb[k] += 1,
+                None => {
+                    atom_type.push(a.type_name.to_string());
+                    atom_numb.push(1);
+                }
+            }
+        }
+        Ok(Some(Frame {
+            frame_idx: self.frame_idx,
+            cell: cell,
+            atom_type: atom_type,
+            atom_numb: atom_numb,
+            natom: natom as i32,
+            atom: atom,
+            velocity_derived: false,
+        }))
     }
-    // println!("basic information is collected!");
-
-    //  ----------Collect the coordination of the frame----------
-    let start : i32 = frameopt[0].parse::<i32>().unwrap();
-    let stop  : i32 = frameopt[1].parse::<i32>().unwrap();
-    let step  : i32 = frameopt[2].parse::<i32>().unwrap();
-    let mut i: i32 = 0 + (start-1)*(9+natom);
-    let mut system: Vec<Frame> = vec![];
-    let mut frame_idx: i32 = start;
-    while i < lines.len().try_into().unwrap() {
-        let mut line: &str = lines[(i as usize)];
-        //println!("{}", i);
-        if frame_idx > stop {
-            break;
+
+    fn skip_frame(&mut self) -> Result<bool, Error> {
+        match self.read_head()? {
+            Some((natom, _, _)) => self.lines.skip_lines(natom),
+            None => Ok(false),
         }
+    }
+}
 
-        if line == "ITEM: TIMESTEP" {  //  seems like unnecessary
-            //println!("i is {}, ITEM: TIMESTEP", i);
-            //frame_idx += 1;
-            i += 9;
-        } else {
-            let mut coord: Vec<Atom> = vec![];
-
-            for j in 0..natom {
-                let mut xyz = lines[((i+j) as usize)]
-                            .split_whitespace().collect::<Vec<&str>>();
-                let atom: Atom = Atom {
-                    index: xyz[0].parse::<i32>().unwrap(),
-                    type_name: xyz[1].to_string(),
-                    coordination: [ xyz[2].parse::<f64>().unwrap(),
-                                   xyz[3].parse::<f64>().unwrap(),
-                                   xyz[4].parse::<f64>().unwrap() ],
-                };
-                coord.push(atom);
+/// read from lammpstrj and return vec![Frame1, Frame2, ...]
+///
+/// frameopt in the form of vec!["#start", "#stop", "#step"],
+/// typemap in the form of vec!["element of type 1", "element of type 2", ...].
+/// All the selected frames are kept in memory, use LammpsReader
+/// (or load::open()) to go through a large file.
+pub fn read_lammpstrj(
+    filename: &str,
+    frameopt: &Vec<&str>,
+    typemap: &Vec<&str>,
+) -> Result<Vec<Frame>, Error> {
+    Frames::new(Box::new(LammpsReader::open(filename, typemap)?), frameopt)?.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// A LineReader of text, written into a temporary file.
+    fn reader(name: &str, text: &str) -> LineReader {
+        let path = std::env::temp_dir().join(format!("lammps_{}_{}", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        let lines = LineReader::open(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        lines
+    }
+
+    #[test]
+    fn tilted_box() {
+        //  lx = 10, ly = 8, lz = 6, xy = 2, xz = -1, yz = 1.5, lo = (0, 0, 0)
+        let mut lines = reader("box", "ITEM: BOX BOUNDS xy xz yz pp pp pp\n\
+                                       -1.0 12.0 2.0\n0.0 9.5 -1.0\n0.0 6.0 1.5\n");
+        let cell = read_box(&mut lines).unwrap();
+        let expect = [[10.0, 0.0, 0.0], [2.0, 8.0, 0.0], [-1.0, 1.5, 6.0]];
+        for i in 0..3 {
+            for j in 0..3 {
+                assert!((cell.matrix[i][j] - expect[i][j]).abs() < 1e-12);
             }
-            system.push(Frame {
-                frame_idx: frame_idx,
-                cell: cell.clone(),
-                atom_type: atom_type.clone(),
-                atom_numb: atom_numb.clone(),
-                natom: natom,
-                atom: coord,
-            });
-            //println!("end of a frame {}", i);
-            i += (natom + 9) * step - 9 ;
-            frame_idx += step;
-            //println!("jumped to {}", i);
         }
+        assert_eq!(cell.origin, [0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn unsorted_scaled_atoms() {
+        let mut cell = Cell::from_lengths([10.0, 10.0, 10.0]);
+        cell.origin = [-5.0, 0.0, 0.0];
+        let typemap: Vec<String> = vec!["O".to_string(), "H".to_string()];
+        let mut lines = reader("xs", "3 2 0.1 0.2 0.3 0.0 0.0 3.0\n\
+                                      1 1 0.5 0.5 0.5 1.0 0.0 0.0\n\
+                                      2 3 0.0 0.0 0.0 0.0 2.0 0.0\n");
+        let atoms = read_atoms("ITEM: ATOMS id type xs ys zs vx vy vz", &mut lines, 3, &cell, &typemap).unwrap();
+        assert_eq!(atoms.iter().map(|x| x.index).collect::<Vec<i32>>(), vec![1, 2, 3]);
+        assert_eq!(atoms.iter().map(|x| x.type_name.as_str()).collect::<Vec<&str>>(), vec!["O", "3", "H"]);
+        assert_eq!(atoms[0].coordination, [0.0, 5.0, 5.0]);
+        assert_eq!(atoms[1].velocity, Some([0.0, 2.0, 0.0]));
+        assert!((atoms[2].coordination[0] + 4.0).abs() < 1e-12);
+        assert_eq!(atoms[2].image, None);
+    }
+
+    #[test]
+    fn unwrapped_atoms() {
+        let cell = Cell::from_lengths([10.0, 10.0, 10.0]);
+        let mut lines = reader("xu", "2 H 12.0 5.0 -3.0\n1 O 1.0 2.0 3.0\n");
+        let atoms = read_atoms("ITEM: ATOMS id element xu yu zu", &mut lines, 2, &cell, &vec![]).unwrap();
+        assert_eq!(atoms[0].image, Some([0, 0, 0]));
+        assert_eq!(atoms[1].type_name, "H");
+        assert_eq!(atoms[1].image, Some([1, 0, -1]));
+        assert!((atoms[1].coordination[0] - 2.0).abs() < 1e-12 && (atoms[1].coordination[2] - 7.0).abs() < 1e-12);
     }
-    // println!("{:?}", system);
-    Ok(system)
-}
\ No newline at end of file
+}
@@ -1,5 +1,240 @@
 //! Load trajectory or configuration information from files such as poscar, trajectory, etc.
+//!
+//! Trajectories are read frame by frame through the FrameReader trait,
+//! so that only one frame is kept in memory. Use open() to get the
+//! selected frames of a file as an iterator.
 
 pub mod qe;
 pub mod vasp;
-pub mod lammps;
\ No newline at end of file
+pub mod lammps;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::str::FromStr;
+use std::vec::IntoIter;
+
+use crate::error::{parse_opt, Error};
+use crate::Frame;
+
+/// Parse the `column`-th word (from 1) of `line`, which is line `lineno` of `file`.
+pub fn parse_word<T: FromStr>(line: &str, column: usize, file: &str, lineno: usize) -> Result<T, Error> {
+    let word = line.split_whitespace().nth(column - 1);
+    match word.map(|x| x.parse::<T>()) {
+        Some(Ok(value)) => Ok(value),
+        _ => Err(Error::Parse {
+            file: file.to_string(),
+            line: lineno,
+            column: column,
+            message: match word {
+                Some(word) => format!("\"{}\" is not a valid {}", word, std::any::type_name::<T>()),
+                None => format!("expect at least {} words in \"{}\"", column, line.trim()),
+            },
+        }),
+    }
+}
+
+/// Read a file line by line, reusing one buffer.
+pub struct LineReader {
+    reader   : BufReader<File>,
+    pub line : String,       // the last line read, without "\n"
+    pub lineno : usize,      // line number of `line`, starting from 1
+    pub filename : String,
+}
+
+impl LineReader {
+    pub fn open(filename: &str) -> Result<LineReader, Error> {
+        let file = File::open(filename).map_err(|e| {
+            Error::Io(std::io::Error::new(e.kind(), format!("{}: {}", filename, e)))
+        })?;
+        Ok(LineReader {
+            reader: BufReader::new(file),
+            line: String::new(),
+            lineno: 0,
+            filename: filename.to_string(),
+        })
+    }
+
+    /// Read the next line into `self.line`, return false at the end of file.
+    pub fn next_line(&mut self) -> Result<bool, Error> {
+        self.line.clear();
+        if self.reader.read_line(&mut self.line)? == 0 {
+            return Ok(false);
+        }
+        while self.line.ends_with('\n') || self.line.ends_with('\r') {
+            self.line.pop();
+        }
+        self.lineno += 1;
+        Ok(true)
+    }
+
+    /// Read the next line and return it, it is an error to hit the end of file.
+    pub fn expect_line(&mut self) -> Result<&str, Error> {
+        if !self.next_line()? {
+            return Err(Error::Format(format!(
+                "unexpected end of file {} after line {}",
+                self.filename, self.lineno
+            )));
+        }
+        Ok(&self.line)
+    }
+
+    /// Parse the `column`-th word (from 1) of the current line.
+    pub fn parse<T: FromStr>(&self, column: usize) -> Result<T, Error> {
+        parse_word(&self.line, column, &self.filename, self.lineno)
+    }
+
+    /// Parse three numbers from the `column`-th word of the current line.
+    pub fn parse3(&self, column: usize) -> Result<[f64; 3], Error> {
+        Ok([self.parse(column)?, self.parse(column + 1)?, self.parse(column + 2)?])
+    }
+
+    /// An Error::Format pointing at the current line.
+    pub fn format_error(&self, message: &str) -> Error {
+        Error::Format(format!("{}, line {}: {}", self.filename, self.lineno, message))
+    }
+
+    /// Skip n lines without keeping them, return false at the end of file.
+    pub fn skip_lines(&mut self, n: usize) -> Result<bool, Error> {
+        let mut buf: Vec<u8> = vec![];
+        for _ in 0..n {
+            buf.clear();
+            if self.reader.read_until(b'\n', &mut buf)? == 0 {
+                return Ok(false);
+            }
+            self.lineno += 1;
+        }
+        Ok(true)
+    }
+
+    /// Byte position of the next line to be read.
+    pub fn position(&mut self) -> Result<u64, Error> {
+        Ok(self.reader.stream_position()?)
+    }
+
+    /// Go to the byte position `pos`, which should be the start of line `lineno`+1.
+    pub fn seek(&mut self, pos: u64, lineno: usize) -> Result<(), Error> {
+        self.reader.seek(SeekFrom::Start(pos))?;
+        self.lineno = lineno;
+        Ok(())
+    }
+}
+
+/// Read a trajectory one frame at a time.
+pub trait FrameReader {
+    /// Read and parse the next frame, None at the end of file.
+    fn read_frame(&mut self) -> Result<Option<Frame>, Error>;
+
+    /// Go over the next frame without parsing it, false at the end of file.
+    fn skip_frame(&mut self) -> Result<bool, Error>;
+
+    /// Go over the next n frames, return how many frames are really skipped.
+    ///
+    /// Readers that know the size of a frame can seek instead.
+    fn skip_frames(&mut self, n: usize) -> Result<usize, Error> {
+        for i in 0..n {
+            if !self.skip_frame()? {
+                return Ok(i);
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// A FrameReader of frames already in memory, used for single frame files.
+pub struct VecReader {
+    frames : IntoIter<Frame>,
+}
+
+impl VecReader {
+    pub fn new(frames: Vec<Frame>) -> VecReader {
+        VecReader { frames: frames.into_iter() }
+    }
+}
+
+impl FrameReader for VecReader {
+    fn read_frame(&mut self) -> Result<Option<Frame>, Error> {
+        Ok(self.frames.next())
+    }
+
+    fn skip_frame(&mut self) -> Result<bool, Error> {
+        Ok(self.frames.next().is_some())
+    }
+}
+
+/// Iterator over the frames start, start+step, ... (<= stop) of a FrameReader,
+/// frames are counted from 1.
+pub struct Frames {
+    reader  : Box<dyn FrameReader>,
+    read    : i32,     // number of frames read or skipped so far
+    next    : i32,     // the next frame to return
+    stop    : i32,
+    step    : i32,
+    done    : bool,
+}
+
+impl Frames {
+    /// frameopt in the form of vec!["#start", "#stop", "#step"]
+    pub fn new(reader: Box<dyn FrameReader>, frameopt: &Vec<&str>) -> Result<Frames, Error> {
+        let start: i32 = parse_opt(frameopt, 0, "start of frameopt")?;
+        let stop: i32 = parse_opt(frameopt, 1, "stop of frameopt")?;
+        let step: i32 = parse_opt(frameopt, 2, "step of frameopt")?;
+        if start < 1 || step < 1 {
+            return Err(Error::InvalidOption(
+                "frameopt should be \"start stop step\" with start >= 1 and step >= 1".to_string(),
+            ));
+        }
+        Ok(Frames { reader, read: 0, next: start, stop, step, done: false })
+    }
+}
+
+impl Iterator for Frames {
+    type Item = Result<Frame, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done || self.next > self.stop {
+            return None;
+        }
+        let gap = (self.next - 1 - self.read) as usize;
+        let frame = match self.reader.skip_frames(gap) {
+            Ok(n) if n < gap => Ok(None),
+            Ok(_) => self.reader.read_frame(),
+            Err(e) => Err(e),
+        };
+        self.read = self.next;
+        self.next += self.step;
+        match frame {
+            Ok(Some(frame)) => Some(Ok(frame)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Open a file of format `fmt` (the format key such as 'vasp/xdatcar')
+/// and return an iterator over the frames selected by frameopt.
+pub fn open(
+    filename: &str,
+    fmt: &str,
+    frameopt: &Vec<&str>,
+    typemap: &Vec<&str>,
+) -> Result<Frames, Error> {
+    let reader: Box<dyn FrameReader> = match fmt {
+        "vasp/poscar" => Box::new(VecReader::new(vasp::read_poscar(filename)?)),
+        "vasp/xdatcar" => Box::new(vasp::XdatcarReader::open(filename)?),
+        "qe/traj" => Box::new(qe::QeReader::open(filename, true)?),
+        "lammps/traj" => Box::new(lammps::LammpsReader::open(filename, typemap)?),
+        _ => {
+            return Err(Error::InvalidOption(format!(
+                "can't read filetype {}, please check your filetype!",
+                fmt
+            )))
+        }
+    };
+    Frames::new(reader, frameopt)
+}
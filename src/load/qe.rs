@@ -4,235 +4,201 @@
 //! The full information of a qe traj is divided into both
 //! .pos and .in.
 //! To use the functions here, add an title like
-//! 
-//! 
-//!     system
-//! 
-//!     1.00
-//! 
-//!       a 0 0
-//! 
-//!       0 b 0
-//! 
-//!       0 0 c
-//! 
-//!     A B C D
-//! 
-//!     1 2 3 4
-//! 
-//! 
+//!
+//! ```text
+//! system
+//! 1.00
+//!   a 0 0
+//!   0 b 0
+//!   0 0 c
+//! A B C D
+//! 1 2 3 4
+//! ```
+//!
 //! into the qe.pos file (in VASP units Angstrom).
+//!
+//! For variable cell runs, the `.cel` file written by QE (same name as the
+//! `.pos` file, e.g. qe.pos -> qe.cel) is read automatically if it exists,
+//! and the cell of each frame is taken from it instead of the title.
+//! In the same way the velocities are read from the `.vel` file (in Hartree
+//! atomic units) if it exists, and kept in Angstrom/ps.
 
-use std::error::Error;
-use std::fs;
+use std::path::Path;
 //use cgmath::Vector3;
 
-use crate::{Atom, Frame};
+use crate::error::Error;
+use crate::load::vasp::read_title;
+use crate::load::{FrameReader, Frames, LineReader};
+use crate::{Atom, Cell, Frame};
 
-/// read from qe/traj and return vec![Frame1, Frame2, ...]
-/// 
-/// The qe file should be added a title like XDATCAR (in Angstrom, not Bohr).
-/// frameopt in the form of vec!["#start", "#stop", "#step"]
-pub fn read_traj(filename: &str, frameopt: &Vec<&str>) -> Result<Vec<Frame>, Box<dyn Error>> {
-    //  ------Collect the basic information of the frame---------
-    let contents = fs::read_to_string(filename)?;
-    let mut lines = vec![];
-    for line in contents.lines() {
-        lines.push(line);
-    }
+/// Bohr radius in Angstrom.
+const BOHR: f64 = 0.529177210903;
 
-    let lx = lines[2]
-        .split_whitespace()
-        .next()
-        .unwrap()
-        .parse::<f64>()
-        .unwrap();
-    let mut ly = lines[3].split_whitespace();
-    ly.next();
-    let ly = ly.next().unwrap().parse::<f64>().unwrap();
-    let mut lz = lines[4].split_whitespace();
-    lz.next();
-    lz.next();
-    let lz = lz.next().unwrap().parse::<f64>().unwrap();
-    let cell: Vec<f64> = vec![lx, ly, lz];
-
-    let mut atom_type: Vec<String> = vec![];
-    for i in lines[5].split_whitespace() {
-        atom_type.push(i.to_string())
-    }
+/// Bohr / atomic unit of time in Angstrom/ps.
+const AU_VELOCITY: f64 = BOHR / 2.4188843265857e-5;
 
-    let mut atom_numb: Vec<i32> = vec![];
-    for i in lines[6].split_whitespace() {
-        atom_numb.push(i.parse().expect("not valid poscar"))
-    }
-
-    //  let xyz_type = lines[7].chars().next().unwrap();
-    //  println!("{:?}",xyz_type);
-
-    let natom: i32 = atom_numb.iter().sum::<i32>();
-
-    //  ----------Collect the coordination of the frame----------
-    let start: i32 = frameopt[0].parse::<i32>().unwrap();
-    let stop: i32 = frameopt[1].parse::<i32>().unwrap();
-    let step: i32 = frameopt[2].parse::<i32>().unwrap();
-    let mut i: i32 = 7 + (start - 1) * (natom + 1);
-    let mut system: Vec<Frame> = vec![];
-    let mut frame_idx: i32 = start - 1;
-    while i < lines.len().try_into().unwrap() {
-        //println!("i = {} ; frame_idx = {}",i, frame_idx);
-        //println!("lines[i] = {:?}\n", lines[i as usize]);
-        //let mut line = lines[(i as usize)].split_whitespace();
-        //println!("{}",i);
-        if (frame_idx - 1) * step + 1 > stop {
-            break;
-        }
-        //println!("{:?}", line.next().unwrap());
-        if (i - 7) % (natom + 1) == 0 {
-            frame_idx += 1;
-            i += 1;
-        } else {
-            let mut coord: Vec<Atom> = vec![];
-            let mut atom_index: i32 = 0;
-            let mut k = 0;
-            for j in 0..natom {
-                //println!("atom_index: {:?}, natom: {}", atom_index, natom);
-                if atom_index >= atom_numb[k] {
-                    atom_index -= atom_numb[k];
-                    k = k + 1;
-                }
-                //println!("{:?}", k);
-                let mut xyz = lines[((i + j) as usize)].split_whitespace();
-                let mut atom = Atom {
-                    index: j + 1,
-                    type_name: atom_type[k].to_string(),
-                    coordination: [
-                        xyz.next().unwrap().parse::<f64>().unwrap() * 0.53,
-                        xyz.next().unwrap().parse::<f64>().unwrap() * 0.53,
-                        xyz.next().unwrap().parse::<f64>().unwrap() * 0.53,
-                    ],
-                };
-                for i in 0..3 {
-                    while atom.coordination[i] < 0.0 {
-                        atom.coordination[i] += cell[i];
-                    }
-                    while atom.coordination[i] > cell[i] {
-                        atom.coordination[i] -= cell[i]
-                    }
-                }
-
-                //println!("{:?}", atom);
-                coord.push(atom);
-                atom_index += 1;
-            }
-            i += (natom + 1) * step - 1;
-            system.push(Frame {
-                frame_idx: (frame_idx - 1) * step + 1,
-                cell: cell.clone(),
-                atom_type: atom_type.clone(),
-                atom_numb: atom_numb.clone(),
-                natom: natom,
-                atom: coord,
-            });
-        }
+/// The reader of the file next to the .pos file with the extension, e.g. qe.cel.
+fn open_next_to(filename: &str, extension: &str) -> Result<Option<LineReader>, Error> {
+    let path = Path::new(filename).with_extension(extension);
+    if path.exists() && path != Path::new(filename) {
+        Ok(Some(LineReader::open(&path.to_string_lossy())?))
+    } else {
+        Ok(None)
     }
+}
 
-    Ok(system)
+/// Read qe.pos (with the title) frame by frame, together with qe.cel and qe.vel if they exist.
+///
+/// With `pbc`, the coordinations are put back into the cell.
+pub struct QeReader {
+    lines     : LineReader,
+    cel       : Option<LineReader>,
+    vel       : Option<LineReader>,
+    pbc       : bool,
+    cell      : Cell,
+    atom_type : Vec<String>,
+    atom_numb : Vec<i32>,
+    natom     : i32,
+    frame_idx : i32,
 }
 
-pub fn read_traj_nopbc(filename: &str, frameopt: &Vec<&str>) -> Result<Vec<Frame>, Box<dyn Error>> {
-    //  ------Collect the basic information of the frame---------
-    let contents = fs::read_to_string(filename)?;
-    let mut lines = vec![];
-    for line in contents.lines() {
-        lines.push(line);
+impl QeReader {
+    pub fn open(filename: &str, pbc: bool) -> Result<QeReader, Error> {
+        let mut lines = LineReader::open(filename)?;
+        let (cell, atom_type, atom_numb) = read_title(&mut lines)?;
+
+        let natom: i32 = atom_numb.iter().sum::<i32>();
+
+        //  the .cel file next to the .pos file, for variable cell runs
+        let cel: Option<LineReader> = open_next_to(filename, "cel")?;
+        let vel: Option<LineReader> = open_next_to(filename, "vel")?;
+
+        Ok(QeReader {
+            lines,
+            cel,
+            vel,
+            pbc,
+            cell,
+            atom_type,
+            atom_numb,
+            natom,
+            frame_idx: 0,
+        })
     }
 
-    let lx = lines[2]
-        .split_whitespace()
-        .next()
-        .unwrap()
-        .parse::<f64>()
-        .unwrap();
-    let mut ly = lines[3].split_whitespace();
-    ly.next();
-    let ly = ly.next().unwrap().parse::<f64>().unwrap();
-    let mut lz = lines[4].split_whitespace();
-    lz.next();
-    lz.next();
-    let lz = lz.next().unwrap().parse::<f64>().unwrap();
-    let cell: Vec<f64> = vec![lx, ly, lz];
-
-    let mut atom_type: Vec<String> = vec![];
-    for i in lines[5].split_whitespace() {
-        atom_type.push(i.to_string())
+    /// Read the next block of the .cel file: one line of `nfi time` followed
+    /// by the three lattice vectors (in Bohr).
+    fn read_cel(&mut self) -> Result<Option<Cell>, Error> {
+        let cel = match &mut self.cel {
+            Some(cel) => cel,
+            None => return Ok(None),
+        };
+        cel.expect_line()?;
+        let mut matrix = [[0.0; 3]; 3];
+        for i in 0..3 {
+            cel.expect_line()?;
+            let xyz: [f64; 3] = cel.parse3(1)?;
+            for j in 0..3 {
+                matrix[i][j] = xyz[j] * BOHR;
+            }
+        }
+        Ok(Some(Cell::new(matrix)))
     }
 
-    let mut atom_numb: Vec<i32> = vec![];
-    for i in lines[6].split_whitespace() {
-        atom_numb.push(i.parse().expect("not valid poscar"))
+    /// Read the next block of the .vel file: one line of `nfi time` followed
+    /// by the velocity of each atom (in atomic units).
+    fn read_vel(&mut self) -> Result<Option<Vec<[f64; 3]>>, Error> {
+        let vel = match &mut self.vel {
+            Some(vel) => vel,
+            None => return Ok(None),
+        };
+        vel.expect_line()?;
+        let mut velocity: Vec<[f64; 3]> = vec![];
+        for _ in 0..self.natom {
+            vel.expect_line()?;
+            let v: [f64; 3] = vel.parse3(1)?;
+            velocity.push([v[0] * AU_VELOCITY, v[1] * AU_VELOCITY, v[2] * AU_VELOCITY]);
+        }
+        Ok(Some(velocity))
     }
+}
 
-    //  let xyz_type = lines[7].chars().next().unwrap();
-    //  println!("{:?}",xyz_type);
-
-    let natom: i32 = atom_numb.iter().sum::<i32>();
-
-    //  ----------Collect the coordination of the frame----------
-    let start: i32 = frameopt[0].parse::<i32>().unwrap();
-    let stop: i32 = frameopt[1].parse::<i32>().unwrap();
-    let step: i32 = frameopt[2].parse::<i32>().unwrap();
-    let mut i: i32 = 7 + (start - 1) * (natom + 1);
-    let mut system: Vec<Frame> = vec![];
-    let mut frame_idx: i32 = start - 1;
-    while i < lines.len().try_into().unwrap() {
-        //println!("i = {} ; frame_idx = {}",i, frame_idx);
-        //println!("lines[i] = {:?}\n", lines[i as usize]);
-        //let mut line = lines[(i as usize)].split_whitespace();
-        //println!("{}",i);
-        if (frame_idx - 1) * step + 1 > stop {
-            break;
+impl FrameReader for QeReader {
+    fn read_frame(&mut self) -> Result<Option<Frame>, Error> {
+        //  "nfi time" line of the block
+        if !self.lines.next_line()? || self.lines.line.trim().is_empty() {
+            return Ok(None);
+        }
+        self.frame_idx += 1;
+        if let Some(cell) = self.read_cel()? {
+            self.cell = cell;
         }
-        //println!("{:?}", line.next().unwrap());
-        if (i - 7) % (natom + 1) == 0 {
-            frame_idx += 1;
-            i += 1;
-        } else {
-            let mut coord: Vec<Atom> = vec![];
-            let mut atom_index: i32 = 0;
-            let mut k = 0;
-            for j in 0..natom {
-                //println!("atom_index: {:?}, natom: {}", atom_index, natom);
-                if atom_index >= atom_numb[k] {
-                    atom_index -= atom_numb[k];
-                    k = k + 1;
-                }
-                //println!("{:?}", k);
-                let mut xyz = lines[((i + j) as usize)].split_whitespace();
-                let atom = Atom {
-                    index: j + 1,
-                    type_name: atom_type[k].to_string(),
-                    coordination: [
-                        xyz.next().unwrap().parse::<f64>().unwrap() * 0.529,
-                        xyz.next().unwrap().parse::<f64>().unwrap() * 0.529,
-                        xyz.next().unwrap().parse::<f64>().unwrap() * 0.529,
-                    ],
-                };
-
-                //println!("{:?}", atom);
-                coord.push(atom);
-                atom_index += 1;
+        let velocity: Option<Vec<[f64; 3]>> = self.read_vel()?;
+        let cell = &self.cell;
+        let atom_type = &self.atom_type;
+        let atom_numb = &self.atom_numb;
+        let mut coord: Vec<Atom> = vec![];
+        let mut atom_index: i32 = 0;
+        let mut k = 0;
+        for j in 0..self.natom {
+            if atom_index >= atom_numb[k] {
+                atom_index -= atom_numb[k];
+                k = k + 1;
+            }
+            self.lines.expect_line()?;
+            let xyz: [f64; 3] = self.lines.parse3(1)?;
+            let mut coordination = [xyz[0] * BOHR, xyz[1] * BOHR, xyz[2] * BOHR];
+            if self.pbc {
+                coordination = cell.wrap(coordination);
             }
-            i += (natom + 1) * step - 1;
-            system.push(Frame {
-                frame_idx: (frame_idx - 1) * step + 1,
-                cell: cell.clone(),
-                atom_type: atom_type.clone(),
-                atom_numb: atom_numb.clone(),
-                natom: natom,
-                atom: coord,
+            coord.push(Atom {
+                index: j + 1,
+                type_name: atom_type[k].to_string(),
+                coordination: coordination,
+                velocity: velocity.as_ref().map(|v| v[j as usize]),
+                ..Default::default()
             });
+            atom_index += 1;
+        }
+        Ok(Some(Frame {
+            frame_idx: self.frame_idx,
+            cell: self.cell.clone(),
+            atom_type: self.atom_type.clone(),
+            atom_numb: self.atom_numb.clone(),
+            natom: self.natom,
+            atom: coord,
+            velocity_derived: false,
+        }))
+    }
+
+    fn skip_frame(&mut self) -> Result<bool, Error> {
+        if !self.lines.skip_lines(self.natom as usize + 1)? {
+            return Ok(false);
+        }
+        if let Some(cel) = &mut self.cel {
+            if !cel.skip_lines(4)? {
+                return Err(cel.format_error("the .cel file ends before the .pos file"));
+            }
+        }
+        if let Some(vel) = &mut self.vel {
+            if !vel.skip_lines(self.natom as usize + 1)? {
+                return Err(vel.format_error("the .vel file ends before the .pos file"));
+            }
         }
+        self.frame_idx += 1;
+        Ok(true)
     }
+}
 
-    Ok(system)
-}
\ No newline at end of file
+/// read from qe/traj and return vec![Frame1, Frame2, ...]
+/// 
+/// The qe file should be added a title like XDATCAR (in Angstrom, not Bohr).
+/// frameopt in the form of vec!["#start", "#stop", "#step"]
+pub fn read_traj(filename: &str, frameopt: &Vec<&str>) -> Result<Vec<Frame>, Error> {
+    Frames::new(Box::new(QeReader::open(filename, true)?), frameopt)?.collect()
+}
+
+/// Same as read_traj(), but the coordinations are not put back into the cell.
+pub fn read_traj_nopbc(filename: &str, frameopt: &Vec<&str>) -> Result<Vec<Frame>, Error> {
+    Frames::new(Box::new(QeReader::open(filename, false)?), frameopt)?.collect()
+}
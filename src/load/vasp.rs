@@ -1,100 +1,110 @@
 //! This module contains functions that read files in VASP format,
 //! including POSCAR and XDATCAR.
 //!
-//! The functions are read_poscar() and read read_xdatcar(), respectively.
+//! The functions are read_poscar() and read read_xdatcar(), respectively,
+//! XdatcarReader reads XDATCAR one frame at a time.
 
-use std::error::Error;
-use std::fs;
-//use cgmath::Vector3;
+use crate::error::Error;
+use crate::load::{FrameReader, Frames, LineReader};
+use crate::{Atom, Cell, Frame};
 
-use crate::{Atom, Frame};
-
-/// read from POSCAR and return vec![Frame1, Frame2, ...]
-pub fn read_poscar(filename: &str) -> Result<Vec<Frame>, Box<dyn Error>> {
-    //  read from POSCAR and return vec![Frame]
-
-    //  ------Collect the basic information of the frame---------
-    //  Basic information of POSCAR is contained in tiile
-
-
-    let contents = fs::read_to_string(filename)?;
-    let mut lines = vec![];
-    for line in contents.lines() {
-        lines.push(line);
+/// Read the title (7 lines) of POSCAR/XDATCAR, i.e. the system name,
+/// the scaling factor, the lattice vectors, the elements and their numbers.
+///
+/// A negative scaling factor is interpreted as the cell volume, as VASP does.
+pub fn read_title(lines: &mut LineReader) -> Result<(Cell, Vec<String>, Vec<i32>), Error> {
+    lines.expect_line()?;
+    lines.expect_line()?;
+    let scale: f64 = lines.parse(1)?;
+    let mut matrix = [[0.0; 3]; 3];
+    for i in 0..3 {
+        lines.expect_line()?;
+        matrix[i] = lines.parse3(1)?;
+    }
+    let factor = if scale < 0.0 {
+        (-scale / Cell::new(matrix).volume()).cbrt()
+    } else {
+        scale
+    };
+    for i in 0..3 {
+        for j in 0..3 {
+            matrix[i][j] *= factor;
+        }
     }
-
-    let lx = lines[2]
-        .split_whitespace()
-        .next()
-        .unwrap()
-        .parse::<f64>()
-        .unwrap();
-    let mut ly = lines[3].split_whitespace();
-    ly.next();
-    let ly = ly.next().unwrap().parse::<f64>().unwrap();
-    let mut lz = lines[4].split_whitespace();
-    lz.next();
-    lz.next();
-    let lz = lz.next().unwrap().parse::<f64>().unwrap();
-    let cell: Vec<f64> = vec![lx, ly, lz];
 
     let mut atom_type: Vec<String> = vec![];
-    for i in lines[5].split_whitespace() {
+    for i in lines.expect_line()?.split_whitespace() {
         atom_type.push(i.to_string())
     }
 
+    lines.expect_line()?;
     let mut atom_numb: Vec<i32> = vec![];
-    for i in lines[6].split_whitespace() {
-        atom_numb.push(i.parse().expect("not valid poscar"))
+    for i in 0..lines.line.split_whitespace().count() {
+        atom_numb.push(lines.parse(i + 1)?)
     }
+    if atom_numb.len() != atom_type.len() {
+        return Err(lines.format_error("numbers of atoms do not match the elements in the line above"));
+    }
+    Ok((Cell::new(matrix), atom_type, atom_numb))
+}
 
-    let xyz_type = lines[7].chars().next().unwrap(); // for VASP, 'D' or 'C'
-                                                     //  println!("{:?}",xyz_type);
-
-    lines = lines.split_off(8);
-
+/// Read the coordinations of natom atoms below the "Direct"/"Cartesian" line.
+///
+/// xyz_type is 'D' (Direct) or 'C' (Cartesian).
+pub fn read_atoms(
+    lines: &mut LineReader,
+    xyz_type: char,
+    cell: &Cell,
+    atom_type: &Vec<String>,
+    atom_numb: &Vec<i32>,
+) -> Result<Vec<Atom>, Error> {
     let natom: i32 = atom_numb.iter().sum::<i32>();
-
-    //  ----------Collect the coordination of the frame----------
     let mut coord: Vec<Atom> = vec![];
     let mut atom_index: i32 = 0;
-    let mut j = 0;
-    for i in 0..natom {
-        if atom_index >= atom_numb[j] {
-            atom_index -= atom_numb[j];
-            j = j + 1
+    let mut k = 0;    //  index to recoord atom_type
+    for j in 0..natom {
+        if atom_index >= atom_numb[k] {
+            atom_index -= atom_numb[k];
+            k = k + 1;
         }
-        let mut xyz = lines[(i as usize)].split_whitespace();
-        let atom: Atom;
-        match xyz_type {
-            //  Cartesian
-            'C' => {
-                atom = Atom {
-                    index: i + 1,
-                    type_name: atom_type[j].to_string(),
-                    coordination: [
-                        xyz.next().unwrap().parse::<f64>().unwrap(),
-                        xyz.next().unwrap().parse::<f64>().unwrap(),
-                        xyz.next().unwrap().parse::<f64>().unwrap(),
-                    ],
-                }
-            }
-            'D' => {
-                atom = Atom {
-                    index: i + 1,
-                    type_name: atom_type[j].to_string(),
-                    coordination: [
-                        xyz.next().unwrap().parse::<f64>().unwrap() * cell[0],
-                        xyz.next().unwrap().parse::<f64>().unwrap() * cell[1],
-                        xyz.next().unwrap().parse::<f64>().unwrap() * cell[2],
-                    ],
-                }
-            }
-            _ => panic!("Cartesian or Direct? Please check your VASP file."),
-        }
-        coord.push(atom);
+        lines.expect_line()?;
+        let xyz: [f64; 3] = lines.parse3(1)?;
+        let coordination: [f64; 3] = match xyz_type {
+            'C' | 'c' | 'K' | 'k' => xyz,              //  Cartesian
+            'D' | 'd' => cell.to_cartesian(xyz),        //  Direct
+            _ => return Err(Error::Format(
+                "Cartesian or Direct? Please check your VASP file.".to_string())),
+        };
+        coord.push(Atom {
+            index: j + 1,
+            type_name: atom_type[k].to_string(),
+            coordination: coordination,
+            ..Default::default()
+        });
         atom_index += 1;
     }
+    Ok(coord)
+}
+
+/// read from POSCAR and return vec![Frame1, Frame2, ...]
+pub fn read_poscar(filename: &str) -> Result<Vec<Frame>, Error> {
+    //  read from POSCAR and return vec![Frame]
+
+    //  ------Collect the basic information of the frame---------
+    //  Basic information of POSCAR is contained in tiile
+    let mut lines = LineReader::open(filename)?;
+    let (cell, atom_type, atom_numb) = read_title(&mut lines)?;
+
+    //  "Selective dynamics" is optional before "Direct" or "Cartesian"
+    let mut xyz_type = lines.expect_line()?.trim().chars().next().unwrap_or(' ');
+    if xyz_type == 'S' || xyz_type == 's' {
+        xyz_type = lines.expect_line()?.trim().chars().next().unwrap_or(' ');
+    }
+
+    let natom: i32 = atom_numb.iter().sum::<i32>();
+
+    //  ----------Collect the coordination of the frame----------
+    let coord: Vec<Atom> = read_atoms(&mut lines, xyz_type, &cell, &atom_type, &atom_numb)?;
 
     let system: Vec<Frame> = vec![Frame {
         frame_idx: 1,
@@ -103,135 +113,242 @@ pub fn read_poscar(filename: &str) -> Result<Vec<Frame>, Box<dyn Error>> {
         atom_numb: atom_numb,
         natom: natom,
         atom: coord,
+        velocity_derived: false,
     }];
     Ok(system)
 }
 
-/// read from XDATCAR and return vec![Frame1, Frame2, ...]
-/// frameopt in the form of vec!["#start", "#stop", "#step"]
-pub fn read_xdatcar(
-    filename: &str,
-    frameopt: &Vec<&str>,
-) -> Result<Vec<Frame>, Box<dyn Error>> {
-    //  read from XDATCAR and return vec![Frame1, Frame2, ...]
-    //  frameopt in the form of vec!["#start", "#stop", "#step"]
+/// Read XDATCAR frame by frame.
+///
+/// Both fixed cell and variable cell XDATCAR are supported, the latter
+/// is recognised by the repeated title and its lattice is read per frame.
+///
+/// ```text
+/// system
+/// ...                        (title of 7 lines, repeated for variable cell)
+/// Direct  configuration=  1
+///  x y z
+/// Direct  configuration=  2
+///  ...
+/// ```
+pub struct XdatcarReader {
+    lines         : LineReader,
+    cell          : Cell,
+    atom_type     : Vec<String>,
+    atom_numb     : Vec<i32>,
+    natom         : i32,
+    variable_cell : bool,
+    frame_bytes   : Option<u64>,   // bytes of one frame, to seek over frames
+    last_config   : i32,           // "configuration=" of the last frame gone over
+}
 
-    //  ------Collect the basic information of the frame---------
-    //  Basic information of POSCAR is contained in tiile
-    //  system
-    //  ...
-    //  Direct  configuration=  1
-    //   x y z
-    //  Direct  configuration=  2
-    //   ...
-    //   ...
-
-    let contents = fs::read_to_string(filename)?;
-    let mut lines = vec![];
-    for line in contents.lines() {
-        lines.push(line);
-    }
-
-    let lx = lines[2]
-        .split_whitespace()
-        .next()
-        .unwrap()
-        .parse::<f64>()
-        .unwrap();
-    let mut ly = lines[3].split_whitespace();
-    ly.next();
-    let ly = ly.next().unwrap().parse::<f64>().unwrap();
-    let mut lz = lines[4].split_whitespace();
-    lz.next();
-    lz.next();
-    let lz = lz.next().unwrap().parse::<f64>().unwrap();
-    let cell: Vec<f64> = vec![lx, ly, lz];
+impl XdatcarReader {
+    pub fn open(filename: &str) -> Result<XdatcarReader, Error> {
+        let mut lines = LineReader::open(filename)?;
+        let (cell, atom_type, atom_numb) = read_title(&mut lines)?;
 
-    let mut atom_type: Vec<String> = vec![];
-    for i in lines[5].split_whitespace() {
-        atom_type.push(i.to_string())
+        let natom: i32 = atom_numb.iter().sum::<i32>();
+
+        //  look at the line after the first frame, another "configuration="
+        //  means the cell is fixed, otherwise the title is repeated.
+        let pos = lines.position()?;
+        let lineno = lines.lineno;
+        lines.skip_lines(1 + natom as usize)?;
+        let variable_cell: bool = lines.next_line()? && !lines.line.contains("configuration");
+        if variable_cell {
+            //  every frame starts with its own title
+            lines.seek(0, 0)?;
+        } else {
+            lines.seek(pos, lineno)?;
+        }
+
+        Ok(XdatcarReader {
+            lines,
+            cell,
+            atom_type,
+            atom_numb,
+            natom,
+            variable_cell,
+            frame_bytes: None,
+            last_config: 0,
+        })
     }
 
-    let mut atom_numb: Vec<i32> = vec![];
-    for i in lines[6].split_whitespace() {
-        atom_numb.push(i.parse().expect("not valid poscar"))
+    /// lines of one frame, 1 + natom or 8 + natom for variable cell
+    fn block(&self) -> usize {
+        if self.variable_cell {
+            self.natom as usize + 8
+        } else {
+            self.natom as usize + 1
+        }
     }
 
-    let xyz_type = lines[7].chars().next().unwrap(); // for VASP, 'D' or 'C'
-                                                     //  println!("{:?}",xyz_type);
+    /// number after "configuration=", or the next number if there is none
+    fn config_number(&self, line: &str) -> i32 {
+        match line.split_whitespace().nth(2) {
+            Some(n) => n.parse::<i32>().unwrap_or(self.last_config + 1),
+            None => self.last_config + 1,
+        }
+    }
 
-    let natom: i32 = atom_numb.iter().sum::<i32>();
+    /// remember the size of a fixed cell frame, for skip_frames()
+    fn record_frame_bytes(&mut self, start: u64) -> Result<(), Error> {
+        if !self.variable_cell && self.frame_bytes.is_none() {
+            self.frame_bytes = Some(self.lines.position()? - start);
+        }
+        Ok(())
+    }
+}
 
-    //  ----------Collect the coordination of the frame----------
-    let start: i32 = frameopt[0].parse::<i32>().unwrap();
-    let stop: i32 = frameopt[1].parse::<i32>().unwrap();
-    let step: i32 = frameopt[2].parse::<i32>().unwrap();
-    let mut i: i32 = 7 + (start - 1) * (natom + 1);
-    let mut system: Vec<Frame> = vec![];
-    let mut frame_idx: i32 = 0;
-    while i < lines.len().try_into().unwrap() {
-        let mut line = lines[(i as usize)].split_whitespace();
-
-        //println!("{}",i);
-        if frame_idx > stop {
-            break;
+impl FrameReader for XdatcarReader {
+    fn read_frame(&mut self) -> Result<Option<Frame>, Error> {
+        let start = self.lines.position()?;
+        if self.variable_cell {
+            let pos = self.lines.position()?;
+            let lineno = self.lines.lineno;
+            if !self.lines.next_line()? || self.lines.line.trim().is_empty() {
+                return Ok(None);
+            }
+            self.lines.seek(pos, lineno)?;
+            let (cell, _, atom_numb) = read_title(&mut self.lines)?;
+            if atom_numb != self.atom_numb {
+                return Err(self.lines.format_error("number of atoms changes between frames"));
+            }
+            self.cell = cell;
+            self.lines.expect_line()?;
+        } else if !self.lines.next_line()? || self.lines.line.trim().is_empty() {
+            return Ok(None);
         }
-        if line.next().unwrap() == "Direct" {
-            line.next();
-            frame_idx = line.next().unwrap().parse::<i32>().unwrap();
-            i += 1;
-        } else {
-            let mut coord: Vec<Atom> = vec![];
-            let mut atom_index: i32 = 0;
-            let mut k = 0;    //  index to recoord atom_type
-            for j in 0..natom {
-                //println!("atom_index: {:?}, natom: {}", atom_index, natom);
-                if atom_index >= atom_numb[k] {
-                    atom_index -= atom_numb[k];
-                    k = k + 1;
-                }
-                //println!("{:?}", k);
-                let mut xyz = lines[((i + j) as usize)].split_whitespace();
-                let atom: Atom;
-                match xyz_type {
-                    'C' => {
-                        atom = Atom {
-                            index: j + 1, //  Cartesian
-                            type_name: atom_type[k].to_string(),
-                            coordination: [
-                                xyz.next().unwrap().parse::<f64>().unwrap(),
-                                xyz.next().unwrap().parse::<f64>().unwrap(),
-                                xyz.next().unwrap().parse::<f64>().unwrap(),
-                            ],
-                        };
-                    }
-                    'D' => {
-                        atom = Atom {
-                            index: j + 1, //  Direct
-                            type_name: atom_type[k].to_string(),
-                            coordination: [
-                                xyz.next().unwrap().parse::<f64>().unwrap() * cell[0],
-                                xyz.next().unwrap().parse::<f64>().unwrap() * cell[1],
-                                xyz.next().unwrap().parse::<f64>().unwrap() * cell[2],
-                            ],
-                        };
-                    }
-                    _ => panic!("Cartesian or Direct? Please check your VASP file."),
+        //  "Direct configuration=  1" or "Cartesian configuration=  1"
+        if !self.lines.line.contains("configuration") {
+            return Err(self.lines.format_error("expect \"Direct configuration=\""));
+        }
+        let frame_idx: i32 = self.config_number(&self.lines.line);
+        let xyz_type = self.lines.line.trim().chars().next().unwrap(); // for VASP, 'D' or 'C'
+
+        let coord: Vec<Atom> = read_atoms(&mut self.lines, xyz_type, &self.cell,
+                                          &self.atom_type, &self.atom_numb)?;
+        self.last_config = frame_idx;
+        self.record_frame_bytes(start)?;
+        Ok(Some(Frame {
+            frame_idx: frame_idx,
+            cell: self.cell.clone(),
+            atom_type: self.atom_type.clone(),
+            atom_numb: self.atom_numb.clone(),
+            natom: self.natom,
+            atom: coord,
+            velocity_derived: false,
+        }))
+    }
+
+    fn skip_frame(&mut self) -> Result<bool, Error> {
+        let start = self.lines.position()?;
+        if !self.lines.skip_lines(self.block())? {
+            return Ok(false);
+        }
+        self.last_config += 1;
+        self.record_frame_bytes(start)?;
+        Ok(true)
+    }
+
+    /// For fixed cell, every frame has the same size in bytes (VASP writes
+    /// fixed width numbers), so seek directly and check the "configuration="
+    /// line we land on; go line by line if it does not match.
+    fn skip_frames(&mut self, n: usize) -> Result<usize, Error> {
+        if let Some(bytes) = self.frame_bytes {
+            if n > 1 {
+                let start = self.lines.position()?;
+                let lineno = self.lines.lineno;
+                let target = start + bytes * n as u64;
+                self.lines.seek(target, lineno + self.block() * n)?;
+                let expect = self.last_config + n as i32 + 1;
+                if self.lines.next_line()?
+                    && self.lines.line.contains("configuration")
+                    && self.config_number(&self.lines.line) == expect
+                {
+                    self.lines.seek(target, lineno + self.block() * n)?;
+                    self.last_config += n as i32;
+                    return Ok(n);
                 }
-                coord.push(atom);
-                atom_index += 1;
+                self.lines.seek(start, lineno)?;
+            }
+        }
+        for i in 0..n {
+            if !self.skip_frame()? {
+                return Ok(i);
             }
-            i += (natom + 1) * step - 1;
-            system.push(Frame {
-                frame_idx: frame_idx,
-                cell: cell.clone(),
-                atom_type: atom_type.clone(),
-                atom_numb: atom_numb.clone(),
-                natom: natom,
-                atom: coord,
-            });
         }
+        Ok(n)
     }
+}
 
-    Ok(system)
+/// read from XDATCAR and return vec![Frame1, Frame2, ...]
+/// frameopt in the form of vec!["#start", "#stop", "#step"]
+///
+/// All the selected frames are kept in memory, use XdatcarReader
+/// (or load::open()) to go through a large file.
+pub fn read_xdatcar(
+    filename: &str,
+    frameopt: &Vec<&str>,
+) -> Result<Vec<Frame>, Error> {
+    Frames::new(Box::new(XdatcarReader::open(filename)?), frameopt)?.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// An XDATCAR of one O and one H, the O of frame k is at x = 0.01 k (fractional)
+    /// and the cell of a variable cell frame is 10 + k.
+    fn write_xdatcar(name: &str, nframe: usize, variable_cell: bool, wide_frame: usize) -> String {
+        let title = |a: f64| format!("water\n1.0\n{:.6} 0 0\n0 {:.6} 0\n0 0 {:.6}\nO H\n1 1\n", a, a, a);
+        let mut text: String = if variable_cell { String::new() } else { title(10.0) };
+        for k in 1..=nframe {
+            if variable_cell {
+                text += &title(10.0 + k as f64);
+            }
+            text += &format!("Direct configuration= {:5}\n", k);
+            text += &format!("  {:.8} 0.50000000 0.50000000\n", 0.01 * k as f64);
+            //  a frame of another size in bytes, the seek of skip_frames() misses
+            let pad: &str = if k == wide_frame { "    " } else { "" };
+            text += &format!("  {:.8} 0.60000000 0.50000000{}\n", 0.01 * k as f64, pad);
+        }
+        let path = std::env::temp_dir().join(format!("xdatcar_{}_{}", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn skip_fixed_cell_frames() {
+        for (name, wide_frame) in [("seek", 0), ("miss", 3)] {
+            let path = write_xdatcar(name, 10, false, wide_frame);
+            let system = read_xdatcar(&path, &vec!["1", "10", "3"]).unwrap();
+            assert_eq!(system.iter().map(|x| x.frame_idx).collect::<Vec<i32>>(), vec![1, 4, 7, 10]);
+            for frame in system.iter() {
+                assert!((frame.atom[0].coordination[0] - 0.1 * frame.frame_idx as f64).abs() < 1e-9);
+            }
+            //  the line numbers are kept through the seek, 7 lines of title and 3 per frame
+            let mut reader = XdatcarReader::open(&path).unwrap();
+            reader.read_frame().unwrap();
+            assert_eq!(reader.skip_frames(3).unwrap(), 3);
+            assert_eq!(reader.read_frame().unwrap().unwrap().frame_idx, 5);
+            assert_eq!(reader.lines.lineno, 7 + 5 * 3);
+            assert_eq!(reader.skip_frames(10).unwrap(), 5);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn variable_cell_frames() {
+        let path = write_xdatcar("npt", 6, true, 0);
+        let system = read_xdatcar(&path, &vec!["2", "6", "2"]).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(system.iter().map(|x| x.frame_idx).collect::<Vec<i32>>(), vec![2, 4, 6]);
+        for frame in system.iter() {
+            let a: f64 = 10.0 + frame.frame_idx as f64;
+            assert!((frame.cell.lengths()[2] - a).abs() < 1e-9);
+            assert!((frame.atom[1].coordination[0] - 0.01 * frame.frame_idx as f64 * a).abs() < 1e-9);
+        }
+    }
 }
@@ -48,6 +48,18 @@ fn main() {
                            .value_name("\"start stop step\"")
                            .required(false)
                            .takes_value(true) )
+                      .arg(Arg::with_name("typemap")
+                           .long("typemap")
+                           .help("Sets the element of each lammps type, e.g. \"O H\" for type 1 and 2")
+                           .value_name("\"element1 element2 ...\"")
+                           .required(false)
+                           .takes_value(true) )
+                      .arg(Arg::with_name("threads")
+                           .long("threads")
+                           .help("Sets the number of threads computing frames in parallel (default: all the cores)")
+                           .value_name("N")
+                           .required(false)
+                           .takes_value(true) )
                       .get_matches();
 
 
@@ -1,126 +1,146 @@
 //! This module contains the main function of computing covalence bond.
-//! For now, this program can only handle for pure water (H-O-H).
+//! Only the water molecules (H-O-H) are used, see task::topology.
+//!
+//! The O-H lengths and the H-O-H angle of each molecule are written, with
+//! their distributions, mean and standard deviation, the averages of each
+//! frame and optionally the joint distribution P(r_OH, theta).
+//!
+//! covopt is optional, the ranges of the histograms are key=min,max,bins
+//! - r=0.8,1.3,100 (Angstrom) for the O-H length
+//! - angle=70,140,140 (degree) for the H-O-H angle
+//! - joint, also write the joint distribution of (r_OH, theta), each O-H
+//!   length of a molecule with its angle
 //!
 //! A commonly command for running this process is
-//! ```
-//! execfile ./a.xdatcar vasp/xdatcar 1 2000 5 cov ./cov.1ps.dat
+//! ```bash
+//! execfile --in ./a.xdatcar --infmt vasp/xdatcar --task cov --taskopt "r=0.8,1.3,100 joint" --out ./cov.dat
 //! ```
 
 use crate::task::{get_angle, get_distance_pbc};
-use crate::{Atom, Frame};
+use crate::task::topology::{Species, Topology, RCUT_COV};
+use crate::task::parallel::map_frames;
+use crate::task::stats::{BlockAverage, Histogram};
+use crate::{Atom, Cell, Frame};
 use std::f64::consts::PI;
-use std::error::Error;
+use crate::error::Error;
 use std::fs;
-use std::fs::OpenOptions;
 use std::io::Write;
 
-//  need to be adapted for numb_type >= 2
-//  consulting the rdf.rs
-
-/// Find the covalence bond for an O atom.
-pub fn find_cov_oneatom<'a>(
-    atom_o: &Atom,
-    coord_H: &'a Vec<&Atom>,
-    cell: &Vec<f64>,
-) -> Result<Vec<&'a Atom>, Box<dyn Error>> {
-    let mut neighbour: Vec<&Atom> = vec![];
-    let mut distance: Vec<(usize, f64)> = vec![];
-    for atom_h in coord_H.iter() {
-        let d = get_distance_pbc(atom_o.coordination, atom_h.coordination, &cell);
-        if d <= 1.5 {
-            //distance.push( ( j.index , i.coordination.distance(j.coordination) ) );
-            distance.push((atom_h.index.try_into().unwrap(), d));
-            neighbour.push(atom_h)
-        }
-        //println!("{:?}", distance[i]);
-        if distance.len() > 1 {
-            // println!("There are more than 2 H close to O, bad!\n");
-            break;
-        }
-    }
-    //distance.sort_by(|a, b| (a.1).partial_cmp(&b.1).unwrap());
-    if neighbour.len() != 2 { panic!("neighbour # of atom {} is not equal to 2", atom_o.index) }
-    Ok(neighbour)
+/// The geometry of one water molecule.
+#[derive(Debug)]
+pub struct Water {
+    pub index    : i32,          // index of the O
+    pub distance : [f64; 2],     // O-H lengths (Angstrom)
+    pub deg      : f64,          // H-O-H angle (degree)
 }
 
-/// Compute the covalence angles (H-O-H) for each water molecule in one frame,
-/// and the each angle will be printed (append) to the output file.
-/// For now, this function is only for pure water.
-pub fn cov_oneframe(frame: &Frame, output: &str) -> Result<(), Box<dyn Error>> {
-    let mut o = OpenOptions::new()
-        .append(true)
-        .open(output)
-        .expect("cannot open file");
-    // let coord: &Vec<Atom> = &frame.xyz;
-    let mut coord_O: Vec<&Atom> = vec![];
-    let mut coord_H: Vec<&Atom> = vec![];
-    //println!("framenumber: {}, \n {:?}",frame.frame_idx,frame.xyz);
-    //let numb_O = frame.atom_numb[0] as usize;
-    //let numb_H = frame.atom_numb[1] as usize;
-    for i in frame.atom.iter() {
-        if i.type_name == "O" || i.type_name == "1" {  // O,H for vasp and 1,2 for lammps
-            coord_O.push(i)
-        }
-        if i.type_name == "H" || i.type_name == "2" {
-            coord_H.push(i)
-        }
-    }
+/// Compute the O-H lengths and the covalence angle (H-O-H, in degree) for each
+/// water molecule in one frame, in the order of the O atoms.
+/// H3O+, OH- and the other molecules are skipped.
+pub fn cov_oneframe(frame: &Frame) -> Result<Vec<Water>, Error> {
+    let mut molecules: Vec<Water> = vec![];
 
-    //  ------Collect the coordination of a and b------
-    //let mut molecules: Vec<Water> = vec![];
-    let cell: &Vec<f64> = &frame.cell;
-    for atom_o in coord_O.iter() {
-        //  atom_o: &Atom
-        let neighbour: Vec<&Atom> = find_cov_oneatom(atom_o, &coord_H, &cell)?;
+    //  ------Collect the water molecules------
+    let cell: &Cell = &frame.cell;
+    let topo = Topology::new(frame, &["O"], RCUT_COV)?;  // use --typemap for lammps
+    for molecule in topo.molecules.iter().filter(|m| m.species == Species::Water) {
+        let atom_o: &Atom = molecule.center;
+        let neighbour: &Vec<&Atom> = &molecule.hydrogen;
         let cov_ang = get_angle(
             neighbour[0].coordination,
             atom_o.coordination,
             neighbour[1].coordination,
             &cell,
         ) * 180.0 / PI;
-        o.write((format!("{:.8}", cov_ang) + "\n").as_bytes())
-            .expect("write cov_angle failed");
-        /*
-        let mut distance: Vec<(usize, f64)> = vec![];
+        molecules.push(Water {
+            index: atom_o.index,
+            distance: [
+                get_distance_pbc(atom_o.coordination, neighbour[0].coordination, &cell),
+                get_distance_pbc(atom_o.coordination, neighbour[1].coordination, &cell),
+            ],
+            deg: cov_ang,
+        });
+    }
+    Ok(molecules)
+}
 
-        for atom_h in coord_H.iter() {
-            let d = get_distance_pbc(atom_o.coordination , atom_h.coordination, &cell);
-            if d <= 1.2 {
-                //distance.push( ( j.index , i.coordination.distance(j.coordination) ) );
-                distance.push( (atom_h.index.try_into().unwrap() , d) );
-            }
-            //println!("{:?}", distance[i]);
-            if distance.len() > 1 {
-                // println!("There are more than 2 H close to O, bad!\n");
-                break
+/// Read "key=min,max,bins" of covopt, default if the key is not given.
+fn histogram_opt(covopt: &Vec<&str>, key: &str, default: (f64, f64, usize)) -> Result<Histogram, Error> {
+    let value: &str = match covopt.iter().find_map(|opt| opt.strip_prefix(key).and_then(|x| x.strip_prefix('='))) {
+        Some(value) => value,
+        None => return Histogram::new(default.0, default.1, default.2),
+    };
+    Histogram::parse(value, key)
+}
+
+/// Compute the geometry of all the water molecules of all the frames.
+///
+/// Four files are written, output: "frame  O  r_OH1  r_OH2  HOH" of each molecule,
+/// output.frame: the averages of each frame with their errors (see task::stats),
+/// output.roh and output.hoh: the distributions of the O-H length and of the angle.
+/// With the option joint, output.joint: "r_OH  theta  P(r_OH, theta)" in blocks of r_OH.
+///
+/// The frames are consumed one batch at a time and computed in parallel.
+pub fn cov<I>(system: I, covopt: &Vec<&str>, output: &str) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
+    let mut hist_r: Histogram = histogram_opt(covopt, "r", (0.8, 1.3, 100))?;
+    let mut hist_a: Histogram = histogram_opt(covopt, "angle", (70.0, 140.0, 140))?;
+    let joint: bool = covopt.contains(&"joint");
+    if let Some(opt) = covopt.iter().find(|x| !(**x == "joint" || x.starts_with("r=") || x.starts_with("angle="))) {
+        return Err(Error::InvalidOption(format!("unknown cov option {}, should be r=, angle= or joint", opt)));
+    }
+    let nr: usize = hist_r.count.len();
+    let na: usize = hist_a.count.len();
+    let mut count_joint: Vec<f64> = vec![0.0; nr * na];
+    let mut stats = BlockAverage::new(2);
+
+    let mut o = fs::File::create(&output)?;
+    let mut o_frame = fs::File::create(format!("{}.frame", output))?;
+    o.write_all("# frame  O  r_OH1  r_OH2  HOH\n".as_bytes())?;
+    o_frame.write_all("# frame  r_OH  HOH\n".as_bytes())?;
+    map_frames(system, cov_oneframe, |i, molecules| {
+        let mut sum: [f64; 2] = [0.0; 2];
+        for m in molecules.iter() {
+            o.write_all(format!("{}  {}  {:.8}  {:.8}  {:.8}\n",
+                i.frame_idx, m.index, m.distance[0], m.distance[1], m.deg).as_bytes())?;
+            hist_a.add(m.deg);
+            for &r in m.distance.iter() {
+                hist_r.add(r);
+                if let (Some(ir), Some(ia)) = (hist_r.bin(r), hist_a.bin(m.deg)) {
+                    count_joint[ir * na + ia] += 1.0;
+                }
             }
+            sum[0] += 0.5 * (m.distance[0] + m.distance[1]);
+            sum[1] += m.deg;
         }
-        //  println!("{:?}",distance);
-        distance.sort_by(|a, b| (a.1).partial_cmp(&b.1).unwrap());
-        //  println!("{:?}", coord[distance[0].0-1]);
-        let dist_avg = ( distance[0].1 + distance[1].1 ) / 2.0;
-        //  println!("{:?}", dist_avg);
-        let cov_ang = get_angle(atom_o.coordination, coord[distance[0].0-1].coordination, coord[distance[1].0-1].coordination, &cell);
-        //  println!("{}, {:?}", atom_o,ang);
-        o.write( (format!("{:.8}", cov_ang) + "\n" ).as_bytes() ).expect("write cov_angle failed");
-        // molecules.push(Water{ O: atom_o,
-        //                       H: (&coord[distance[0].0-1], &coord[distance[1].0-1]),
-        //                       distance: dist_avg,
-        //                       deg: cov_ang
-        //                     });
-        */
-    }
-    //println!("{:?}", molecules);
-    //Ok( molecules )
-    Ok(())
-}
+        //  a frame without water has no average
+        if !molecules.is_empty() {
+            let average: [f64; 2] = [sum[0] / molecules.len() as f64, sum[1] / molecules.len() as f64];
+            o_frame.write_all(format!("{}  {:.8}  {:.8}\n", i.frame_idx, average[0], average[1]).as_bytes())?;
+            stats.push(&average);
+        }
+        Ok(())
+    })?;
+    stats.write(&mut o_frame, &["r_OH".to_string(), "HOH".to_string()])?;
+    println!("r_OH: mean {:.6} std {:.6},  HOH: mean {:.4} std {:.4}",
+        hist_r.mean(), hist_r.std(), hist_a.mean(), hist_a.std());
 
-/// Create the output file and put all covalence angle of all the frames
-/// into the output file.
-pub fn cov(system: &Vec<Frame>, output: &str) -> Result<(), Box<dyn Error>> {
-    fs::File::create(&output).unwrap();
-    for i in system.iter() {
-        cov_oneframe(i, &output)?;
+    hist_r.write(&mut fs::File::create(format!("{}.roh", output))?, "r_OH")?;
+    hist_a.write(&mut fs::File::create(format!("{}.hoh", output))?, "HOH")?;
+    if joint {
+        //  normalised to 1 in the range of both r_OH and HOH
+        let norm: f64 = count_joint.iter().sum::<f64>().max(1.0) * hist_r.width() * hist_a.width();
+        let mut o_joint = fs::File::create(format!("{}.joint", output))?;
+        o_joint.write_all("# r_OH  HOH  P(r_OH,HOH)\n".as_bytes())?;
+        for ir in 0..nr {
+            for ia in 0..na {
+                o_joint.write_all(format!("{:.6}  {:.6}  {:.8}\n",
+                    hist_r.center(ir), hist_a.center(ia), count_joint[ir * na + ia] / norm).as_bytes())?;
+            }
+            o_joint.write_all("\n".as_bytes())?;
+        }
     }
     Ok(())
 }
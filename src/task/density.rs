@@ -0,0 +1,290 @@
+//! Profiles along one axis of the cell, for interfaces and slabs (water/vacuum,
+//! water/metal).
+//!
+//! The cell is cut into bins along the fractional coordinate of a lattice
+//! vector (x, y, z for a, b, c, the normal of the slab for the usual cells with
+//! the vacuum or the metal along c), so that each bin has the volume V/bins.
+//! The position of a bin is its center, from the origin of the cell, in A of
+//! the height of the cell along the axis averaged over the frames.
+//!
+//! - density: the number density (1/A^3) of each element and the mass density
+//!   (g/cm^3) of all the atoms, with its error (see task::stats)
+//! - slab: in each bin of the O (the heavy atoms of the HB definition for the
+//!   HBs), the number of molecules per frame, the number of HBs (donated and
+//!   accepted) per molecule, q of task::q and the orientation of the water
+//!   dipole (O to the middle of the two H) with the axis, <cos(theta)> and
+//!   <P2(cos(theta))>, NaN for an empty bin
+//!
+//! densityopt is "axis=z bins=100", slabopt is "axis=z bins=20" with a HB
+//! definition of task hb, all optional, e.g.
+//! ```bash
+//! execfile --in ./a.xdatcar --infmt vasp/xdatcar --task density --taskopt "axis=z bins=200" --out ./density.dat
+//! execfile --in ./a.xdatcar --infmt vasp/xdatcar --task slab --taskopt "axis=z bins=20 luzar" --out ./slab.dat
+//! ```
+
+use crate::error::Error;
+use crate::task::element::mass;
+use crate::task::get_vector_pbc;
+use crate::task::hb::{count_donor_acceptor, find_HBs, HbDefinition};
+use crate::task::parallel::map_frames;
+use crate::task::q::{neighbour_vectors, tetrahedral, Shell};
+use crate::task::stats::BlockAverage;
+use crate::task::topology::{Species, Topology, RCUT_COV};
+use crate::{Cell, Frame};
+use std::fs;
+use std::io::Write;
+
+/// g/mol/A^3 in g/cm^3, i.e. 10 / N_A.
+const MASS_DENSITY: f64 = 1.0 / 0.602214076;
+
+/// The bins along one axis of the cell.
+#[derive(Debug, Clone, Copy)]
+pub struct Profile {
+    pub axis : usize,   // 0, 1, 2 for a, b, c
+    pub bins : usize,
+}
+
+impl Profile {
+    /// Read axis= and bins= from the task option, the other words are returned for the task.
+    pub fn from_opt<'a>(opt: &[&'a str], bins: usize) -> Result<(Profile, Vec<&'a str>), Error> {
+        let mut profile = Profile { axis: 2, bins };
+        let mut others: Vec<&str> = vec![];
+        for word in opt.iter() {
+            match word.split_once('=') {
+                Some(("axis", value)) => profile.axis = match value {
+                    "x" | "a" => 0,
+                    "y" | "b" => 1,
+                    "z" | "c" => 2,
+                    _ => return Err(Error::InvalidOption(format!("axis should be x, y or z, not {}", value))),
+                },
+                Some(("bins", value)) => profile.bins = value.parse().map_err(|_| Error::InvalidOption(format!(
+                    "can't parse bins from \"{}\"", value)))?,
+                _ => others.push(word),
+            }
+        }
+        if profile.bins == 0 {
+            return Err(Error::InvalidOption("bins of the profile should be positive".to_string()));
+        }
+        Ok((profile, others))
+    }
+
+    /// The bin of a position, from its fractional coordinate along the axis.
+    pub fn bin(&self, cell: &Cell, cart: [f64; 3]) -> usize {
+        let s: f64 = cell.to_fractional([
+            cart[0] - cell.origin[0],
+            cart[1] - cell.origin[1],
+            cart[2] - cell.origin[2],
+        ])[self.axis];
+        (((s - s.floor()) * self.bins as f64) as usize).min(self.bins - 1)
+    }
+
+    /// The unit vector normal to the bins, along the axis.
+    pub fn normal(&self, cell: &Cell) -> [f64; 3] {
+        let a = cell.matrix[(self.axis + 1) % 3];
+        let b = cell.matrix[(self.axis + 2) % 3];
+        let n = [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]];
+        let norm: f64 = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+        n.map(|x| x / norm)
+    }
+
+    /// Height (A) of the cell along the axis, V / |b x c| for a.
+    pub fn height(&self, cell: &Cell) -> f64 {
+        let n = self.normal(cell);
+        let v = cell.matrix[self.axis];
+        (v[0] * n[0] + v[1] * n[1] + v[2] * n[2]).abs()
+    }
+
+    /// Position (A) of the center of bin i, the first column of the output files.
+    fn position(&self, i: usize, height: f64) -> f64 {
+        (i as f64 + 0.5) / self.bins as f64 * height
+    }
+}
+
+/// Number of atoms of each element and the mass (g/mol) of all the atoms in each bin,
+/// the elements in the order of the frame.
+fn density_oneframe(frame: &Frame, profile: &Profile) -> Result<(Vec<(String, Vec<f64>)>, Vec<f64>), Error> {
+    let mut count: Vec<(String, Vec<f64>)> = vec![];
+    let mut mass_bin: Vec<f64> = vec![0.0; profile.bins];
+    for atom in frame.atom.iter() {
+        let k: usize = profile.bin(&frame.cell, atom.coordination);
+        let e: usize = match count.iter().position(|x| x.0 == atom.type_name) {
+            Some(e) => e,
+            None => {
+                count.push((atom.type_name.clone(), vec![0.0; profile.bins]));
+                count.len() - 1
+            }
+        };
+        count[e].1[k] += 1.0;
+        mass_bin[k] += mass(&atom.type_name)?;
+    }
+    Ok((count, mass_bin))
+}
+
+/// Compute the density profile of all the frames.
+///
+/// output: "position(A)  rho_element...(1/A^3)  rho_mass(g/cm^3)  err_mass", the densities
+/// are averaged over the frames. The frames are consumed one batch at a time and
+/// computed in parallel.
+pub fn density<I>(system: I, densityopt: &Vec<&str>, output: &str) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
+    let (profile, others) = Profile::from_opt(densityopt, 100)?;
+    if !others.is_empty() {
+        return Err(Error::InvalidOption(format!("unknown density option {:?}, should be axis= or bins=", others)));
+    }
+    let mut number: Vec<(String, Vec<f64>)> = vec![];
+    let mut height: f64 = 0.0;
+    let mut nframe: usize = 0;
+    let mut stats = BlockAverage::new(profile.bins);
+    map_frames(system, |i| density_oneframe(i, &profile), |i, (count, mass_bin)| {
+        let volume: f64 = i.cell.volume() / profile.bins as f64;
+        for (name, c) in count {
+            let e: usize = match number.iter().position(|x| x.0 == name) {
+                Some(e) => e,
+                None => {
+                    number.push((name, vec![0.0; profile.bins]));
+                    number.len() - 1
+                }
+            };
+            for k in 0..profile.bins {
+                number[e].1[k] += c[k] / volume;
+            }
+        }
+        stats.push(&mass_bin.iter().map(|m| m / volume * MASS_DENSITY).collect::<Vec<f64>>());
+        height += profile.height(&i.cell);
+        nframe += 1;
+        Ok(())
+    })?;
+    if nframe == 0 {
+        return Err(Error::InvalidOption("no frame for density, please check frameopt".to_string()));
+    }
+    let height: f64 = height / nframe as f64;
+    let rho_mass: Vec<f64> = stats.mean();
+    let (block_size, error) = stats.error();
+
+    let mut o = fs::File::create(output)?;
+    o.write_all(format!("# {} frames, height {:.4} A along {}, error by block averaging, block_size {}\n",
+                    nframe, height, ["x", "y", "z"][profile.axis], block_size).as_bytes())?;
+    o.write_all(format!("# position(A){}  rho_mass(g/cm3)  err_mass\n",
+                    number.iter().map(|x| format!("  rho_{}(1/A3)", x.0)).collect::<String>()).as_bytes())?;
+    for k in 0..profile.bins {
+        let line: String = number.iter().map(|x| format!("  {:.8}", x.1[k] / nframe as f64)).collect();
+        o.write_all(format!("{:.4}{}  {:.6}  {:.6}\n", profile.position(k, height), line, rho_mass[k], error[k]).as_bytes())?;
+    }
+    println!("Average mass density is {:.6} g/cm3", rho_mass.iter().sum::<f64>() / profile.bins as f64);
+    Ok(())
+}
+
+/// Sums of one bin of task slab.
+#[derive(Debug, Clone, Copy, Default)]
+struct SlabSum {
+    numb_molecule : f64,
+    numb_hb       : f64,   // donated + accepted, of the molecules in the bin
+    numb_q        : f64,
+    q             : f64,
+    numb_water    : f64,
+    cos           : f64,   // of the dipole with the axis
+    p2            : f64,
+}
+
+/// Sums of each bin in one frame, the molecules are put into the bin of the heavy atom.
+fn slab_oneframe(frame: &Frame, profile: &Profile, def: &HbDefinition) -> Result<Vec<SlabSum>, Error> {
+    let cell: &Cell = &frame.cell;
+    let mut sum: Vec<SlabSum> = vec![SlabSum::default(); profile.bins];
+    let (hbonds, coord_heavy) = find_HBs(frame, def)?;
+    for (atom, (donor, acceptor)) in coord_heavy.iter().zip(count_donor_acceptor(&hbonds, &coord_heavy)) {
+        let k: usize = profile.bin(cell, atom.coordination);
+        sum[k].numb_molecule += 1.0;
+        sum[k].numb_hb += (donor + acceptor) as f64;
+    }
+    for (atom, vectors) in neighbour_vectors(frame, &Shell::default()) {
+        if let Some(v) = vectors {
+            let k: usize = profile.bin(cell, atom.coordination);
+            sum[k].numb_q += 1.0;
+            sum[k].q += tetrahedral(&v);
+        }
+    }
+    let n = profile.normal(cell);
+    let topo = Topology::new(frame, &["O"], RCUT_COV)?;
+    for m in topo.molecules.iter().filter(|m| m.species == Species::Water) {
+        let mut dipole = [0.0; 3];
+        for h in m.hydrogen.iter() {
+            let v = get_vector_pbc(m.center.coordination, h.coordination, cell);
+            for d in 0..3 {
+                dipole[d] += v[d];
+            }
+        }
+        let norm: f64 = (dipole[0] * dipole[0] + dipole[1] * dipole[1] + dipole[2] * dipole[2]).sqrt();
+        let cos: f64 = (dipole[0] * n[0] + dipole[1] * n[1] + dipole[2] * n[2]) / norm;
+        let k: usize = profile.bin(cell, m.center.coordination);
+        sum[k].numb_water += 1.0;
+        sum[k].cos += cos;
+        sum[k].p2 += 1.5 * cos * cos - 0.5;
+    }
+    Ok(sum)
+}
+
+/// Compute the per-slab HBs, q and orientation of all the frames.
+///
+/// output: "position(A)  molecule  nHB  q  cos  P2", the number of molecules per frame
+/// and the averages of the molecules of each bin over all the frames.
+/// The frames are consumed one batch at a time and computed in parallel.
+pub fn slab<I>(system: I, slabopt: &Vec<&str>, output: &str) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
+    let (profile, hbopt) = Profile::from_opt(slabopt, 20)?;
+    let def = HbDefinition::from_opt(&hbopt)?;
+    println!("HB definition: {:?}", def);
+    let mut total: Vec<SlabSum> = vec![SlabSum::default(); profile.bins];
+    let mut height: f64 = 0.0;
+    let mut nframe: usize = 0;
+    map_frames(system, |i| slab_oneframe(i, &profile, &def), |i, sum| {
+        for (t, s) in total.iter_mut().zip(sum) {
+            t.numb_molecule += s.numb_molecule;
+            t.numb_hb += s.numb_hb;
+            t.numb_q += s.numb_q;
+            t.q += s.q;
+            t.numb_water += s.numb_water;
+            t.cos += s.cos;
+            t.p2 += s.p2;
+        }
+        height += profile.height(&i.cell);
+        nframe += 1;
+        Ok(())
+    })?;
+    if nframe == 0 {
+        return Err(Error::InvalidOption("no frame for slab, please check frameopt".to_string()));
+    }
+    let height: f64 = height / nframe as f64;
+
+    let mut o = fs::File::create(output)?;
+    o.write_all(format!("# {} frames, height {:.4} A along {}, dipole angle with +{}\n",
+                    nframe, height, ["x", "y", "z"][profile.axis], ["x", "y", "z"][profile.axis]).as_bytes())?;
+    o.write_all("# position(A)  molecule  nHB  q  cos  P2\n".as_bytes())?;
+    for (k, t) in total.iter().enumerate() {
+        o.write_all(format!("{:.4}  {:.4}  {:.6}  {:.6}  {:.6}  {:.6}\n", profile.position(k, height),
+                        t.numb_molecule / nframe as f64, t.numb_hb / t.numb_molecule, t.q / t.numb_q,
+                        t.cos / t.numb_water, t.p2 / t.numb_water).as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bins_of_tilted_cell() {
+        let mut cell = Cell::new([[10.0, 0.0, 0.0], [0.0, 10.0, 0.0], [3.0, 0.0, 20.0]]);
+        cell.origin = [0.0, 0.0, -5.0];
+        let (profile, others) = Profile::from_opt(&["bins=4", "luzar"], 100).unwrap();
+        assert_eq!((profile.axis, profile.bins), (2, 4));
+        assert_eq!(others, vec!["luzar"]);
+        assert!((profile.height(&cell) - 20.0).abs() < 1e-12);
+        //  z = 1 is 6 A above the origin, the second bin; below the origin is wrapped to the top
+        assert_eq!(profile.bin(&cell, [9.0, 1.0, 1.0]), 1);
+        assert_eq!(profile.bin(&cell, [0.0, 0.0, -6.0]), 3);
+    }
+}
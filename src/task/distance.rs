@@ -2,33 +2,40 @@
 //! For now, this program can only handle for pure water (H-O-H).
 //!
 //! A commonly command for running this process is
-//! ```
+//! ```bash
 //! execfile ./a.xdatcar vasp/xdatcar 1 2000 5 distance atom1_index atom2_index ./dist.dat
 //! ```
 
 use crate::task::get_distance_pbc;
 use crate::Frame;
-use std::error::Error;
+use crate::error::{parse_opt, Error};
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::Write;
 
-pub fn compute_distance(
-    system: &Vec<Frame>,
+pub fn compute_distance<I>(
+    system: I,
     rdfopt: &Vec<&str>,
     output: &str,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
 
-    let index1: usize = rdfopt[0].parse::<usize>().unwrap();
-    let index2: usize = rdfopt[1].parse::<usize>().unwrap();
-    let mut o = fs::File::create(output).unwrap();
+    let index1: usize = parse_opt(rdfopt, 0, "atom1_index")?;
+    let index2: usize = parse_opt(rdfopt, 1, "atom2_index")?;
+    let mut o = fs::File::create(output)?;
 
-    for (i, frame) in system.iter().enumerate() {
+    for (i, frame) in system.enumerate() {
+        let frame: Frame = frame?;
+        if index1 < 1 || index2 < 1 || index1 > frame.atom.len() || index2 > frame.atom.len() {
+            return Err(Error::InvalidOption(format!(
+                "atom index should be in 1..={}", frame.atom.len())));
+        }
         let dist: f64 = get_distance_pbc(frame.atom[index1-1].coordination, 
                                     frame.atom[index2-1].coordination, 
                                     &frame.cell);
-        o.write((format!("{:.4}  {:.8}", i, dist) + "\n").as_bytes())
-            .expect("write rdf to file failed");
+        o.write_all((format!("{:.4}  {:.8}", i, dist) + "\n").as_bytes())?;
     }
     Ok(())
 }
@@ -0,0 +1,98 @@
+//! Time correlation functions by FFT (Wiener-Khinchin theorem).
+//!
+//! The series is padded with zeros to twice its length, so that the result is
+//! the linear (not circular) correlation, in O(N log N) instead of O(N^2).
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use std::sync::Arc;
+
+/// Correlation of series of one length, the FFT plans are made once.
+pub struct Correlator {
+    n      : usize,
+    fft    : Arc<dyn Fft<f64>>,
+    ifft   : Arc<dyn Fft<f64>>,
+}
+
+impl Correlator {
+    /// For series of length n.
+    pub fn new(n: usize) -> Correlator {
+        let mut planner = FftPlanner::new();
+        Correlator {
+            n,
+            fft: planner.plan_fft_forward(2 * n),
+            ifft: planner.plan_fft_inverse(2 * n),
+        }
+    }
+
+    /// sum_{k=0}^{n-1-m} x(k) y(k+m) for m = 0..n, i.e. not divided by the number of origins.
+    pub fn correlation(&self, x: &[f64], y: &[f64]) -> Vec<f64> {
+        let mut fx: Vec<Complex<f64>> = vec![Complex::new(0.0, 0.0); 2 * self.n];
+        let mut fy: Vec<Complex<f64>> = vec![Complex::new(0.0, 0.0); 2 * self.n];
+        for k in 0..self.n {
+            fx[k].re = x[k];
+            fy[k].re = y[k];
+        }
+        self.fft.process(&mut fx);
+        self.fft.process(&mut fy);
+        for k in 0..2 * self.n {
+            fx[k] = fx[k].conj() * fy[k];
+        }
+        self.ifft.process(&mut fx);
+        //  rustfft does not normalise the inverse transform
+        fx[..self.n].iter().map(|c| c.re / (2 * self.n) as f64).collect()
+    }
+
+    /// sum_{k=0}^{n-1-m} x(k) x(k+m) for m = 0..n.
+    pub fn autocorrelation(&self, x: &[f64]) -> Vec<f64> {
+        let mut fx: Vec<Complex<f64>> = vec![Complex::new(0.0, 0.0); 2 * self.n];
+        for k in 0..self.n {
+            fx[k].re = x[k];
+        }
+        self.fft.process(&mut fx);
+        for c in fx.iter_mut() {
+            *c = Complex::new(c.norm_sqr(), 0.0);
+        }
+        self.ifft.process(&mut fx);
+        fx[..self.n].iter().map(|c| c.re / (2 * self.n) as f64).collect()
+    }
+}
+
+/// Mean square displacement of a series for all the lags m = 0..n,
+/// averaged over the n-m time origins.
+///
+/// msd(m) = S1(m) - 2 S2(m), S1 from a running sum of x^2 and S2 the autocorrelation.
+pub fn msd_fft(x: &[f64], correlator: &Correlator) -> Vec<f64> {
+    let n: usize = x.len();
+    let s2: Vec<f64> = correlator.autocorrelation(x);
+    let square: Vec<f64> = x.iter().map(|v| v * v).collect();
+    let mut q: f64 = 2.0 * square.iter().sum::<f64>();
+    let mut msd: Vec<f64> = vec![0.0; n];
+    for m in 0..n {
+        if m > 0 {
+            q -= square[m - 1] + square[n - m];
+        }
+        msd[m] = (q - 2.0 * s2[m]) / (n - m) as f64;
+    }
+    msd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_as_direct_sum() {
+        let x: Vec<f64> = (0..37).map(|i| (i as f64 * 0.7).sin() + 0.05 * i as f64).collect();
+        let n = x.len();
+        let correlator = Correlator::new(n);
+        let msd = msd_fft(&x, &correlator);
+        let acf = correlator.correlation(&x, &x);
+        for m in 0..n {
+            let direct: f64 = (0..n - m).map(|k| (x[k + m] - x[k]).powi(2)).sum::<f64>() / (n - m) as f64;
+            assert!((msd[m] - direct).abs() < 1e-9);
+            let direct: f64 = (0..n - m).map(|k| x[k] * x[k + m]).sum();
+            assert!((acf[m] - direct).abs() < 1e-9);
+        }
+    }
+}
@@ -1,82 +1,221 @@
 //! This module contains the main function of computing hydrogen bonds.
+//!
+//! The HB definition is set by the task option, the first word is the criterion
+//! and the others are "key=value" pairs, e.g.
+//! ```bash
+//! --taskopt "geometric roo=3.3 angle=150 convention=oho donor=O,N acceptor=O,N,Cl"
+//! ```
+//! - luzar (default): Luzar-Chandler, R(O-O) < 3.5 A and angle(H-O...O) < 30 degree
+//! - geometric: keys roo (3.5), roh (the H...A distance, not used by default),
+//!   angle and convention, "hoo" for angle(H-D...A) < angle (30 by default)
+//!   or "oho" for angle(D-H...A) > angle (150 by default)
+//! - skinner: the electronic-structure-based criterion of Kumar, Schmidt and
+//!   Skinner (J. Chem. Phys. 126, 204107, 2007), the fitted NBO occupancy
+//!   exp(-R(H...A)/0.343) (7.1 - 0.05 theta + 0.00021 theta^2) > occupancy
+//!   with theta = angle(H-D...A) in degree, key occupancy (0.0085)
+//! - kss: the geometric criterion of the same paper that mimics it,
+//!   R(H...A) < b - a*theta^2, keys a (0.00044) and b (2.3)
+//!
+//! The keys donor and acceptor are the elements (separated by ",") that donate
+//! (with their covalent H) or accept HBs, both are "O" by default.
 
-use crate::task::cov::find_cov_oneatom;
 use crate::task::{get_angle, get_distance_pbc};
-use crate::{Atom, Frame};
-use std::f64::consts::PI;
-use std::error::Error;
+use crate::task::neighbour::CellList;
+use crate::task::topology::{Topology, RCUT_COV};
+use crate::task::parallel::map_frames;
+use crate::task::stats::BlockAverage;
+use crate::{Atom, Cell, Frame};
+use crate::error::Error;
+use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
 
-/// This function computes the average HBs on each water molecule in one frame.
-pub fn compute_HBs_oneframe(frame: &Frame) -> Result<f64, Box<dyn Error>> {
-    //  ------Collect the information of O and H in this frame------
-    let cell: &Vec<f64> = &frame.cell;
-    let mut coord_O: Vec<&Atom> = vec![];
-    let mut coord_H: Vec<&Atom> = vec![];
-    //let numb_O = frame.atom_numb[0] as usize;
-    //let numb_H = frame.atom_numb[1] as usize;
-    for i in frame.atom.iter() {
-        if i.type_name == "O" || i.type_name == "1" {
-            coord_O.push(i)
+/// The criterion to decide whether D-H...A is a hydrogen bond.
+#[derive(Clone, Debug)]
+pub enum Criterion {
+    /// r_da: max D...A, r_ha: max H...A, angle in rad,
+    /// hoo: angle(H-D...A) < angle if true, angle(D-H...A) > angle if false
+    Geometric { r_da: f64, r_ha: f64, angle: f64, hoo: bool },
+    /// exp(-R(H...A)/0.343) (7.1 - 0.05 theta + 0.00021 theta^2) > occupancy,
+    /// theta = angle(H-D...A) in degree
+    Skinner { occupancy: f64 },
+    /// R(H...A) < b - a*theta^2, theta = angle(H-D...A) in degree
+    Kss { a: f64, b: f64 },
+}
+
+/// The HB definition: the criterion, and the elements of donors and acceptors.
+#[derive(Clone, Debug)]
+pub struct HbDefinition {
+    pub criterion : Criterion,
+    pub donor     : Vec<String>,
+    pub acceptor  : Vec<String>,
+}
+
+/// One hydrogen bond D-H...A, given by the index of the atoms.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+pub struct HBond {
+    pub donor    : i32,
+    pub hydrogen : i32,
+    pub acceptor : i32,
+}
+
+impl HbDefinition {
+    /// Read the definition from the task option, see the module document.
+    pub fn from_opt(hbopt: &Vec<&str>) -> Result<HbDefinition, Error> {
+        let name: &str = hbopt.get(0).cloned().unwrap_or("luzar");
+        let mut keys: Vec<(&str, &str)> = vec![];
+        for opt in hbopt.iter().skip(1) {
+            match opt.split_once('=') {
+                Some(kv) => keys.push(kv),
+                None => return Err(Error::InvalidOption(format!(
+                    "hb option \"{}\" should be in the form of key=value", opt))),
+            }
+        }
+        let mut used: Vec<&str> = vec!["donor", "acceptor"];
+        let mut get = |key: &'static str, default: f64| -> Result<f64, Error> {
+            used.push(key);
+            match keys.iter().find(|kv| kv.0 == key) {
+                Some(kv) => kv.1.parse::<f64>().map_err(|_| Error::InvalidOption(format!(
+                    "can't parse {} of hb from \"{}\"", key, kv.1))),
+                None => Ok(default),
+            }
+        };
+        let criterion = match name {
+            "luzar" => Criterion::Geometric {
+                r_da: 3.5, r_ha: f64::INFINITY, angle: 30.0_f64.to_radians(), hoo: true },
+            "geometric" => {
+                let hoo = match keys.iter().find(|kv| kv.0 == "convention").map(|kv| kv.1) {
+                    None | Some("hoo") => true,
+                    Some("oho") => false,
+                    Some(x) => return Err(Error::InvalidOption(format!(
+                        "convention of hb should be hoo or oho, not {}", x))),
+                };
+                let angle: f64 = get("angle", if hoo { 30.0 } else { 150.0 })?;
+                Criterion::Geometric {
+                    r_da: get("roo", 3.5)?,
+                    r_ha: get("roh", f64::INFINITY)?,
+                    angle: angle.to_radians(),
+                    hoo,
+                }
+            }
+            "skinner" => {
+                let occupancy: f64 = get("occupancy", 0.0085)?;
+                if occupancy <= 0.0 {
+                    return Err(Error::InvalidOption(format!("occupancy of hb should be positive, not {}", occupancy)));
+                }
+                Criterion::Skinner { occupancy }
+            }
+            "kss" => Criterion::Kss { a: get("a", 0.00044)?, b: get("b", 2.3)? },
+            _ => return Err(Error::InvalidOption(format!(
+                "unknown hb criterion {}, should be luzar, geometric, skinner or kss", name))),
+        };
+        if name == "geometric" {
+            used.push("convention");
         }
-        if i.type_name == "H" || i.type_name == "2" {
-            coord_H.push(i)
+        if let Some(kv) = keys.iter().find(|kv| !used.contains(&kv.0)) {
+            return Err(Error::InvalidOption(format!("unknown key {} for hb criterion {}", kv.0, name)));
         }
+        let elements = |key: &str| -> Vec<String> {
+            match keys.iter().find(|kv| kv.0 == key) {
+                Some(kv) => kv.1.split(',').filter(|x| !x.is_empty()).map(|x| x.to_string()).collect(),
+                None => vec!["O".to_string()],
+            }
+        };
+        Ok(HbDefinition { criterion, donor: elements("donor"), acceptor: elements("acceptor") })
     }
 
-    // ------Main loop to compute #HBs------
-    let mut total_numb_HB: i32 = 0;
+    /// The largest D...A distance of a HB, used to search the acceptors.
+    pub fn r_search(&self) -> f64 {
+        match self.criterion {
+            Criterion::Geometric { r_da, r_ha, .. } => r_da.min(r_ha + RCUT_COV),
+            //  the angular factor is the largest (7.1) at theta = 0
+            Criterion::Skinner { occupancy } => 0.343 * (7.1 / occupancy).ln() + RCUT_COV,
+            Criterion::Kss { b, .. } => b + RCUT_COV,
+        }
+    }
 
-    for atom_o1 in coord_O.iter() {
-        //  let mut numb_accept: i32 = 0;
-        //  let mut numb_donate: i32 = 0;
-        let mut numb_HB: i32 = 0;
-        for atom_o2 in coord_O.iter() {
-            if atom_o1.index == atom_o2.index {
-                continue;
-            }
-            //  println!("atom1: {:?} \n atom2: {:?}", atom_o1, atom_o2);
-            if get_distance_pbc(atom_o1.coordination, atom_o2.coordination, cell) < 3.5 {
-                //  compute #HB_accept
-                let neighbour_o2: Vec<&Atom> = find_cov_oneatom(atom_o2, &coord_H, &cell)?;
-                for atom_h in neighbour_o2.iter() {
-                    if get_angle(
-                        atom_o1.coordination,
-                        atom_o2.coordination,
-                        atom_h.coordination,
-                        &cell,
-                    ) < (PI / 6.0)
-                    {
-                        //  numb_accept += 1;
-                        numb_HB += 1;
-                    }
+    /// Whether D-H...A is a HB, given the positions.
+    pub fn is_hb(&self, d: [f64; 3], h: [f64; 3], a: [f64; 3], cell: &Cell) -> bool {
+        match self.criterion {
+            Criterion::Geometric { r_da, r_ha, angle, hoo } => {
+                if get_distance_pbc(d, a, cell) >= r_da {
+                    return false;
                 }
-                //  compute #HB_donate
-                let neighbour_o1: Vec<&Atom> = find_cov_oneatom(atom_o1, &coord_H, &cell)?;
-                for atom_h in neighbour_o1.iter() {
-                    if get_angle(
-                        atom_o2.coordination,
-                        atom_o1.coordination,
-                        atom_h.coordination,
-                        &cell,
-                    ) < (PI / 6.0)
-                    {
-                        //  numb_donate += 1;
-                        numb_HB += 1;
-                    }
+                if r_ha.is_finite() && get_distance_pbc(h, a, cell) >= r_ha {
+                    return false;
+                }
+                if hoo {
+                    get_angle(a, d, h, cell) < angle
+                } else {
+                    get_angle(d, h, a, cell) > angle
                 }
             }
-            //if numb_HB >= 4 { continue }
+            Criterion::Skinner { occupancy } => {
+                let theta = get_angle(a, d, h, cell).to_degrees();
+                let r_ha = get_distance_pbc(h, a, cell);
+                (-r_ha / 0.343).exp() * (7.1 - 0.05 * theta + 0.00021 * theta.powi(2)) > occupancy
+            }
+            Criterion::Kss { a: c2, b } => {
+                let theta = get_angle(a, d, h, cell).to_degrees();
+                get_distance_pbc(h, a, cell) < b - c2 * theta.powi(2)
+            }
         }
+    }
 
-        //if numb_accept >= 4 || numb_donate >= 4 {
-        //    panic!("#HB_accept is {} or #HB_donate is {} for atom {:?}", numb_accept, numb_donate, atom_o1);
-        //}
-        //println!("{:?}", numb_HB);
+    /// Whether the element can be a donor or an acceptor.
+    fn is_heavy(&self, type_name: &str) -> bool {
+        self.donor.iter().chain(self.acceptor.iter()).any(|x| x == type_name)
+    }
+}
 
-        total_numb_HB += numb_HB;
+/// Find all the HBs in one frame, sorted by (donor, hydrogen, acceptor).
+///
+/// Also return the donors and acceptors (in the order of the atoms),
+/// each H belongs to its nearest heavy atom, so H3O+ may donate three H and OH- one.
+pub fn find_HBs<'a>(frame: &'a Frame, def: &HbDefinition) -> Result<(Vec<HBond>, Vec<&'a Atom>), Error> {
+    let cell: &Cell = &frame.cell;
+    let heavy: Vec<&str> = frame
+        .atom_type
+        .iter()
+        .map(|x| x.as_str())
+        .filter(|x| def.is_heavy(x))
+        .collect();
+    let topo = Topology::new(frame, &heavy, RCUT_COV)?;
+    let coord_heavy: Vec<&Atom> = topo.molecules.iter().map(|m| m.center).collect();
+    let acceptor: Vec<&Atom> = coord_heavy
+        .iter()
+        .filter(|x| def.acceptor.contains(&x.type_name))
+        .cloned()
+        .collect();
+
+    //  D-A pairs within the search radius from the cell list
+    let r_search: f64 = def.r_search();
+    let list = CellList::new(acceptor.iter().map(|x| x.coordination).collect(), cell, r_search);
+    let mut hbonds: Vec<HBond> = vec![];
+    for molecule in topo.molecules.iter().filter(|m| def.donor.contains(&m.center.type_name)) {
+        let atom_d: &Atom = molecule.center;
+        for (j, _) in list.within(atom_d.coordination, r_search) {
+            let atom_a: &Atom = acceptor[j];
+            if atom_a.index == atom_d.index {
+                continue;
+            }
+            for atom_h in molecule.hydrogen.iter() {
+                if def.is_hb(atom_d.coordination, atom_h.coordination, atom_a.coordination, cell) {
+                    hbonds.push(HBond { donor: atom_d.index, hydrogen: atom_h.index, acceptor: atom_a.index });
+                }
+            }
+        }
     }
+    hbonds.sort();
+    Ok((hbonds, coord_heavy))
+}
+
+/// This function computes the average HBs on each molecule (donor or acceptor) in one frame.
+///
+/// Each HB is counted for both its donor and its acceptor.
+pub fn compute_HBs_oneframe(frame: &Frame, def: &HbDefinition) -> Result<f64, Error> {
+    let (hbonds, coord_heavy) = find_HBs(frame, def)?;
+    let total_numb_HB: i32 = 2 * hbonds.len() as i32;
     /*
     for atom_o1 in coord_O.iter() {
         //let mut numb_accept: i32 = 0;
@@ -109,24 +248,149 @@ pub fn compute_HBs_oneframe(frame: &Frame) -> Result<f64, Box<dyn Error>> {
         total_numb_HB += numb_HB;
     }*/
 
-    let avg_numb_HB: f64 = total_numb_HB as f64 / coord_O.len() as f64;
+    let avg_numb_HB: f64 = total_numb_HB as f64 / coord_heavy.len() as f64;
     //println!("{:?}", avg_numb_HB);
     Ok(avg_numb_HB)
 }
 
 /// This function collects all the average HBs of each frame and give an answer.
-pub fn compute_HBs(system: &Vec<Frame>, output: &str) -> Result<(), Box<dyn Error>> {
-    let mut o = fs::File::create(&output).unwrap();
-    let mut avg_numb_HB: f64 = 0.0;
-    for i in system.iter() {
-        let numb_HB_oneframe: f64 = compute_HBs_oneframe(i)?;
+/// hbopt is the HB definition, see the module document.
+/// The average is followed by its error and the block size table (see task::stats).
+///
+/// The frames are consumed one batch at a time and computed in parallel.
+pub fn compute_HBs<I>(system: I, hbopt: &Vec<&str>, output: &str) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
+    let def = HbDefinition::from_opt(hbopt)?;
+    println!("HB definition: {:?}", def);
+    let mut o = fs::File::create(&output)?;
+    let mut stats = BlockAverage::new(1);
+    map_frames(system, |i| compute_HBs_oneframe(i, &def), |i, numb_HB_oneframe| {
         println!("{}  {}", &i.frame_idx, &numb_HB_oneframe);
-        o.write((format!("{:.4}  {:.8}", i.frame_idx, numb_HB_oneframe) + "\n").as_bytes())
-            .expect("write hb to file failed");
-        avg_numb_HB += numb_HB_oneframe;
+        o.write_all((format!("{:.4}  {:.8}", i.frame_idx, numb_HB_oneframe) + "\n").as_bytes())?;
+        stats.push(&[numb_HB_oneframe]);
+        Ok(())
+    })?;
+    let avg_numb_HB: f64 = stats.mean()[0];
+    println!("{:.8} +- {:.8}", avg_numb_HB, stats.error().1[0]);
+    o.write_all( format!("#avg {:.8}\n", avg_numb_HB).as_bytes()  )?;
+    stats.write(&mut o, &["nHB".to_string()])?;
+    Ok(())
+}
+
+/// Largest number of donated and accepted HBs of a class in hb_population,
+/// molecules with more HBs are counted as "other".
+const MAX_DONOR: usize = 3;
+const MAX_ACCEPTOR: usize = 4;
+
+/// Number of HBs (donated, accepted) of each atom in coord_heavy.
+pub fn count_donor_acceptor(hbonds: &[HBond], coord_heavy: &[&Atom]) -> Vec<(usize, usize)> {
+    let position: HashMap<i32, usize> = coord_heavy.iter().enumerate().map(|(i, x)| (x.index, i)).collect();
+    let mut count: Vec<(usize, usize)> = vec![(0, 0); coord_heavy.len()];
+    for hb in hbonds.iter() {
+        count[position[&hb.donor]].0 += 1;
+        count[position[&hb.acceptor]].1 += 1;
+    }
+    count
+}
+
+/// Name of a class such as "DDAA" (two donated and two accepted HBs), "-" for no HB.
+pub fn class_name(numb_donor: usize, numb_acceptor: usize) -> String {
+    let name = "D".repeat(numb_donor) + &"A".repeat(numb_acceptor);
+    if name.is_empty() { "-".to_string() } else { name }
+}
+
+/// The donor/acceptor breakdown of the HBs of each molecule.
+///
+/// Three files are written, output: the population of each class (e.g. DDAA, DAA,
+/// DDA) over all the frames, output.frame: the population of each class in each
+/// frame, output.mol: the number of donated/accepted HBs of each molecule in each frame.
+///
+/// hbopt is the HB definition, see the module document.
+/// The frames are consumed one batch at a time and computed in parallel.
+pub fn hb_population<I>(system: I, hbopt: &Vec<&str>, output: &str) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
+    let def = HbDefinition::from_opt(hbopt)?;
+    println!("HB definition: {:?}", def);
+    let mut classes: Vec<(usize, usize)> = vec![];
+    for numb_donor in 0..=MAX_DONOR {
+        for numb_acceptor in 0..=MAX_ACCEPTOR {
+            classes.push((numb_donor, numb_acceptor));
+        }
     }
-    avg_numb_HB = avg_numb_HB / system.len() as f64;
-    println!("{:?}", avg_numb_HB);
-    o.write( format!("#avg {:.8}", avg_numb_HB).as_bytes()  ).expect("write avg hb to file failed");
+    let nclass: usize = classes.len();
+
+    let mut o_frame = fs::File::create(format!("{}.frame", output))?;
+    let mut o_mol = fs::File::create(format!("{}.mol", output))?;
+    let mut header: String = "# frame".to_string();
+    for c in classes.iter() {
+        header += &format!("  {}", class_name(c.0, c.1));
+    }
+    o_frame.write_all((header + "  other\n").as_bytes())?;
+    o_mol.write_all("# frame  index  type  donor  acceptor\n".as_bytes())?;
+
+    //  number of molecules in each class (and the others at the end), summed over frames
+    let mut total: Vec<f64> = vec![0.0; nclass + 1];
+    let mut numb_molecule: f64 = 0.0;
+    let one_frame = |frame: &Frame| -> Result<Vec<(i32, String, (usize, usize))>, Error> {
+        let (hbonds, coord_heavy) = find_HBs(frame, &def)?;
+        let count = count_donor_acceptor(&hbonds, &coord_heavy);
+        Ok(coord_heavy.iter().zip(count).map(|(x, c)| (x.index, x.type_name.clone(), c)).collect())
+    };
+    map_frames(system, one_frame, |i, molecules| {
+        let mut population: Vec<f64> = vec![0.0; nclass + 1];
+        for (index, type_name, c) in molecules.iter() {
+            o_mol.write_all(format!("{}  {}  {}  {}  {}\n", i.frame_idx, index, type_name, c.0, c.1).as_bytes())?;
+            if c.0 <= MAX_DONOR && c.1 <= MAX_ACCEPTOR {
+                population[c.0 * (MAX_ACCEPTOR + 1) + c.1] += 1.0;
+            } else {
+                population[nclass] += 1.0;
+            }
+        }
+        let mut line: String = format!("{}", i.frame_idx);
+        for j in 0..nclass + 1 {
+            total[j] += population[j];
+            line += &format!("  {:.6}", population[j] / molecules.len() as f64);
+        }
+        o_frame.write_all((line + "\n").as_bytes())?;
+        numb_molecule += molecules.len() as f64;
+        Ok(())
+    })?;
+
+    //  ------the histogram over all the frames------
+    let mut o = fs::File::create(&output)?;
+    o.write_all("# class  donor  acceptor  population\n".as_bytes())?;
+    for (j, c) in classes.iter().enumerate() {
+        o.write_all(format!("{}  {}  {}  {:.8}\n", class_name(c.0, c.1), c.0, c.1, total[j] / numb_molecule).as_bytes())?;
+    }
+    o.write_all(format!("other  -  -  {:.8}\n", total[nclass] / numb_molecule).as_bytes())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether D-H...A is a HB, D at the origin, H at 1 A along x and
+    /// A at r_da from D with angle(H-D...A) = theta (degree).
+    fn is_hb(hbopt: &str, r_da: f64, theta: f64) -> bool {
+        let def = HbDefinition::from_opt(&hbopt.split_whitespace().collect()).unwrap();
+        let a = [r_da * theta.to_radians().cos(), r_da * theta.to_radians().sin(), 0.0];
+        def.is_hb([0.0; 3], [1.0, 0.0, 0.0], a, &Cell::from_lengths([20.0, 20.0, 20.0]))
+    }
+
+    #[test]
+    fn skinner_and_kss() {
+        for name in ["skinner", "kss"] {
+            assert!(is_hb(name, 2.8, 5.0));
+            assert!(!is_hb(name, 3.5, 5.0));
+        }
+        //  R(H...A) = 2.0 A at 30 degree, within the occupancy but beyond the quadratic
+        assert!(is_hb("skinner", 2.8, 30.0));
+        assert!(!is_hb("kss", 2.8, 30.0));
+        assert!(!is_hb("skinner occupancy=0.02", 2.8, 30.0));
+    }
+}
@@ -0,0 +1,144 @@
+//! This module contains the HB time correlation functions and lifetimes.
+//!
+//! With h(t) = 1 if a given D-H...A is a HB at time t (see task::hb), and
+//! H(t) = 1 if it has been a HB all the time from 0 to t,
+//! - intermittent: C(t) = <h(0)h(t)> / <h>
+//! - continuous:   S(t) = <h(0)H(t)> / <h>
+//!
+//! averaged over all the D-H...A and time origins. The lifetimes are the
+//! integrals of C(t) and S(t) (trapezoidal rule) up to the largest lag.
+//!
+//! A typical command is
+//! ```bash
+//! execfile --in ./a.xdatcar --infmt vasp/xdatcar --task hblife --taskopt "200 0.01 luzar" --out ./hblife.dat
+//! ```
+
+use crate::error::{parse_opt, Error};
+use crate::task::hb::{find_HBs, HBond, HbDefinition};
+use crate::task::parallel::map_frames;
+use crate::Frame;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+
+/// C(t) and S(t) for t = 0..numb_lag (in frames) from the frames in which
+/// each HB exists (sorted, counted from 0), nframe is the total number of frames.
+pub fn correlation(history: &Vec<Vec<usize>>, nframe: usize, numb_lag: usize) -> (Vec<f64>, Vec<f64>) {
+    let mut intermittent: Vec<f64> = vec![0.0; numb_lag];
+    let mut continuous: Vec<f64> = vec![0.0; numb_lag];
+    let mut norm: Vec<f64> = vec![0.0; numb_lag];
+    let mut exist: Vec<bool> = vec![false; nframe];
+    for frames in history.iter() {
+        for &t in frames.iter() {
+            exist[t] = true;
+        }
+        //  <h(0)h(t)> and <h> over the time origins t0 <= nframe-1-t
+        for lag in 0..numb_lag {
+            for &t0 in frames.iter() {
+                if t0 + lag >= nframe {
+                    break;
+                }
+                norm[lag] += 1.0;
+                if exist[t0 + lag] {
+                    intermittent[lag] += 1.0;
+                }
+            }
+        }
+        //  a run of L frames adds L-t to <h(0)H(t)>
+        let mut start: usize = 0;
+        for k in 1..=frames.len() {
+            if k == frames.len() || frames[k] != frames[k - 1] + 1 {
+                let length: usize = k - start;
+                for lag in 0..numb_lag.min(length) {
+                    continuous[lag] += (length - lag) as f64;
+                }
+                start = k;
+            }
+        }
+        for &t in frames.iter() {
+            exist[t] = false;
+        }
+    }
+    for lag in 0..numb_lag {
+        if norm[lag] > 0.0 {
+            intermittent[lag] /= norm[lag];
+            continuous[lag] /= norm[lag];
+        }
+    }
+    (intermittent, continuous)
+}
+
+/// Integral of f (trapezoidal rule) with the interval dt.
+fn integrate(f: &Vec<f64>, dt: f64) -> f64 {
+    if f.len() < 2 {
+        return 0.0;
+    }
+    let sum: f64 = f.iter().sum::<f64>() - 0.5 * (f[0] + f[f.len() - 1]);
+    sum * dt
+}
+
+/// Compute C(t), S(t) and the lifetimes, and write "t  C(t)  S(t)" into the output file.
+///
+/// hbopt = vec!["numb_lag", "dt", hb definition ...], numb_lag is the largest lag
+/// in frames, dt is the time (ps) between two frames used, the HB definition
+/// is the same as task hb (see task::hb).
+/// The HBs of the frames are found in parallel and all kept in memory.
+pub fn hb_lifetime<I>(system: I, hbopt: &Vec<&str>, output: &str) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
+    let numb_lag: usize = parse_opt(hbopt, 0, "numb_lag")?;
+    let dt: f64 = parse_opt(hbopt, 1, "dt")?;
+    if numb_lag < 1 || dt <= 0.0 {
+        return Err(Error::InvalidOption("numb_lag and dt of hblife should be positive".to_string()));
+    }
+    let def = HbDefinition::from_opt(&hbopt[2..].to_vec())?;
+    println!("HB definition: {:?}", def);
+
+    //  ------the frames in which each D-H...A is a HB------
+    let mut history: HashMap<HBond, Vec<usize>> = HashMap::new();
+    let mut nframe: usize = 0;
+    map_frames(system, |frame| Ok(find_HBs(frame, &def)?.0), |_, hbonds| {
+        for hb in hbonds {
+            history.entry(hb).or_default().push(nframe);
+        }
+        nframe += 1;
+        Ok(())
+    })?;
+    if nframe < 2 {
+        return Err(Error::InvalidOption(format!("hblife needs at least 2 frames, {} given", nframe)));
+    }
+    let numb_lag: usize = numb_lag.min(nframe);
+    //  the order of the HBs does not change the answer, but keep it reproducible
+    let mut history: Vec<(HBond, Vec<usize>)> = history.into_iter().collect();
+    history.sort();
+    let history: Vec<Vec<usize>> = history.into_iter().map(|x| x.1).collect();
+    println!("{} frames, {} different HBs", nframe, history.len());
+
+    let (intermittent, continuous) = correlation(&history, nframe, numb_lag);
+    let tau_c: f64 = integrate(&intermittent, dt);
+    let tau_s: f64 = integrate(&continuous, dt);
+    println!("Intermittent lifetime is {:.6} ps, continuous lifetime is {:.6} ps", tau_c, tau_s);
+
+    //  ------write into output file------
+    let mut o = fs::File::create(output)?;
+    o.write_all(format!("# tau_C {:.8} ps  tau_S {:.8} ps\n", tau_c, tau_s).as_bytes())?;
+    o.write_all("# t(ps)  C(t)  S(t)\n".as_bytes())?;
+    for lag in 0..numb_lag {
+        o.write_all(format!("{:.4}  {:.8}  {:.8}\n", lag as f64 * dt, intermittent[lag], continuous[lag]).as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intermittent_and_continuous() {
+        //  one HB in frames 0, 1 and 3 of 4 frames
+        let (c, s) = correlation(&vec![vec![0, 1, 3]], 4, 3);
+        assert_eq!(c, vec![1.0, 0.5, 0.5]);
+        assert_eq!(s, vec![1.0, 0.5, 0.0]);
+    }
+}
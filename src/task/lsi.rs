@@ -0,0 +1,132 @@
+//! The order parameters of the two-state picture of water, next to task::q.
+//!
+//! - LSI, the local structure index of Shiratani and Sasai (J. Chem. Phys.
+//!   104, 7671 (1996)): with the O-O distances r_1 < r_2 < ... < r_n < rcut
+//!   < r_{n+1} of a molecule, LSI = 1/n sum_j (d_j - d_mean)^2 with
+//!   d_j = r_{j+1} - r_j, in A^2. A large LSI means a well separated first
+//!   shell (low density, tetrahedral), a small one an interstitial molecule.
+//! - zeta of Russo and Tanaka (Nat. Commun. 5, 3556 (2014)): the distance of
+//!   the nearest molecule not hydrogen bonded to it minus the distance of the
+//!   farthest one hydrogen bonded (donated or accepted, see task::hb), in A.
+//!
+//! The output files are those of task::q (per molecule, per frame and the
+//! distribution, whose two peaks can be fitted for the two states).
+//! lsiopt is "rcut=3.7 hist=0,0.3,150", zetaopt is a HB definition of task hb
+//! with "hist=-1.5,1.5,150", all optional, e.g.
+//! ```bash
+//! execfile --in ./a.xdatcar --infmt vasp/xdatcar --task lsi --out ./lsi.dat
+//! execfile --in ./a.xdatcar --infmt vasp/xdatcar --task zeta --taskopt "luzar hist=-1,2,150" --out ./zeta.dat
+//! ```
+
+use crate::error::Error;
+use crate::task::hb::{find_HBs, HbDefinition};
+use crate::task::neighbour::CellList;
+use crate::task::q::write_order_parameter;
+use crate::task::stats::Histogram;
+use crate::{Atom, Frame};
+use std::collections::HashSet;
+
+/// Largest distance (A) of the molecules searched for LSI and zeta.
+const R_SEARCH: f64 = 6.0;
+
+/// LSI from the sorted distances of the neighbours, None without a neighbour
+/// within rcut or without one beyond it.
+pub fn local_structure_index(distance: &[f64], rcut: f64) -> Option<f64> {
+    let n: usize = distance.iter().filter(|&&r| r < rcut).count();
+    if n == 0 || n == distance.len() {
+        return None;
+    }
+    let gap: Vec<f64> = (0..n).map(|j| distance[j + 1] - distance[j]).collect();
+    let mean: f64 = gap.iter().sum::<f64>() / n as f64;
+    Some(gap.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / n as f64)
+}
+
+/// Compute LSI of each O in one frame as (index, LSI), in the order of the atoms.
+pub fn lsi_oneframe(frame: &Frame, rcut: f64) -> Result<Vec<(i32, Option<f64>)>, Error> {
+    let coord_O: Vec<&Atom> = frame.atom.iter().filter(|x| x.type_name == "O").collect();
+    let list = CellList::new(coord_O.iter().map(|x| x.coordination).collect(), &frame.cell, R_SEARCH.max(rcut + 1.0));
+    Ok(coord_O.iter().map(|atom| {
+        let distance: Vec<f64> = list.nearest(atom.coordination, usize::MAX, 0.1).iter().map(|x| x.1).collect();
+        (atom.index, local_structure_index(&distance, rcut))
+    }).collect())
+}
+
+/// Compute zeta of each donor/acceptor in one frame as (index, zeta), in the order of
+/// the atoms, None without a HB or without a molecule not hydrogen bonded within R_SEARCH.
+pub fn zeta_oneframe(frame: &Frame, def: &HbDefinition) -> Result<Vec<(i32, Option<f64>)>, Error> {
+    let (hbonds, coord_heavy) = find_HBs(frame, def)?;
+    let bonded: HashSet<(i32, i32)> = hbonds.iter()
+        .flat_map(|hb| [(hb.donor, hb.acceptor), (hb.acceptor, hb.donor)])
+        .collect();
+    let list = CellList::new(coord_heavy.iter().map(|x| x.coordination).collect(), &frame.cell, R_SEARCH);
+    Ok(coord_heavy.iter().map(|atom| {
+        let mut farthest_hb: Option<f64> = None;
+        let mut nearest_other: Option<f64> = None;
+        for (j, r) in list.nearest(atom.coordination, usize::MAX, 0.1) {
+            if bonded.contains(&(atom.index, coord_heavy[j].index)) {
+                farthest_hb = Some(r);
+            } else if nearest_other.is_none() {
+                nearest_other = Some(r);
+            }
+        }
+        let zeta: Option<f64> = match (farthest_hb, nearest_other) {
+            (Some(hb), Some(other)) => Some(other - hb),
+            _ => None,
+        };
+        (atom.index, zeta)
+    }).collect())
+}
+
+/// Compute LSI of all the frames, see task::q::write_order_parameter() for the output files.
+pub fn lsi<I>(system: I, lsiopt: &Vec<&str>, output: &str) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
+    let mut rcut: f64 = 3.7;
+    let mut hist: Histogram = Histogram::new(0.0, 0.3, 150)?;
+    for opt in lsiopt.iter() {
+        match opt.split_once('=') {
+            Some(("rcut", value)) => rcut = value.parse().map_err(|_| Error::InvalidOption(format!(
+                "can't parse rcut of lsi from \"{}\"", value)))?,
+            Some(("hist", value)) => hist = Histogram::parse(value, "LSI")?,
+            _ => return Err(Error::InvalidOption(format!("unknown lsi option {}, should be rcut= or hist=", opt))),
+        }
+    }
+    if rcut <= 0.0 {
+        return Err(Error::InvalidOption("rcut of lsi should be positive".to_string()));
+    }
+    write_order_parameter(system, output, "LSI", hist, |i| lsi_oneframe(i, rcut))
+}
+
+/// Compute zeta of all the frames, see task::q::write_order_parameter() for the output files.
+pub fn zeta<I>(system: I, zetaopt: &Vec<&str>, output: &str) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
+    let mut hist: Histogram = Histogram::new(-1.5, 1.5, 150)?;
+    let mut hbopt: Vec<&str> = vec![];
+    for opt in zetaopt.iter() {
+        match opt.strip_prefix("hist=") {
+            Some(value) => hist = Histogram::parse(value, "zeta")?,
+            None => hbopt.push(opt),
+        }
+    }
+    let def = HbDefinition::from_opt(&hbopt)?;
+    println!("HB definition: {:?}", def);
+    write_order_parameter(system, output, "zeta", hist, |i| zeta_oneframe(i, &def))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lsi_of_shells() {
+        //  even gaps in the first shell, nothing to order
+        assert!(local_structure_index(&[2.8, 3.0, 3.2, 3.4, 3.6, 3.8], 3.7).unwrap() < 1e-12);
+        //  gaps 0.1, 0.1, 0.7: mean 0.3, LSI = (0.04 + 0.04 + 0.16) / 3
+        let lsi: f64 = local_structure_index(&[2.7, 2.8, 2.9, 3.6], 3.0).unwrap();
+        assert!((lsi - 0.08).abs() < 1e-12);
+        assert_eq!(local_structure_index(&[2.8, 2.9], 3.7), None);
+    }
+}
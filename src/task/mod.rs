@@ -4,46 +4,43 @@
 //! get_distance_pbc(), get_angle()
 
 pub mod cov;
+pub mod density;
 pub mod hb;
+pub mod hblife;
+pub mod lsi;
 pub mod msd;
+pub mod neighbour;
+pub mod parallel;
 pub mod q;
 pub mod rdf;
+pub mod stats;
+pub mod steinhardt;
+pub mod topology;
+pub mod unwrap;
+pub mod vacf;
+pub mod velocity;
+pub mod vanhove;
 pub mod distance;
+pub mod element;
+pub mod fft;
 
-use crate::{Atom, Frame};
-use std::error::Error;
+use crate::Cell;
 use std::f64::consts::PI;
-use std::fmt;
 
-pub struct Water<'a> {
-    O: &'a Atom,
-    H: (&'a Atom, &'a Atom),
-    distance: f64,
-    deg: f64,
-}
-
-impl<'a> fmt::Debug for Water<'a> {
-    //  print{:?} for struct Water
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            " O: {:?}\n H1: {:?}\n H2: {:?}\n dist_avg: {}\n cov_angle:{}\n",
-            self.O.coordination, self.H.0.coordination, self.H.1.coordination, self.distance, self.deg
-        )
-    }
+/// Compute the vector from point a to point b.
+///
+/// considering the periodic boundary condition (minimum image),
+/// works for triclinic cells as well.
+pub fn get_vector_pbc(a: [f64; 3], b: [f64; 3], cell: &Cell) -> [f64; 3] {
+    let delta = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+    cell.minimum_image(delta)
 }
 
 /// Compute the distance between two points a and b.
 /// 
 /// considering the periodic boundary condition.
-pub fn get_distance_pbc(a: [f64; 3], b: [f64; 3], cell: &Vec<f64>) -> f64 {
-    let vector1 = a.clone();
-    let vector2 = b.clone();
-    let mut delta = vec![0.0, 0.0, 0.0];
-    for i in 0..3 {
-        delta[i] = vector2[i] - vector1[i];
-        delta[i] = delta[i] - (delta[i] / cell[i]).round() * cell[i];
-    }
+pub fn get_distance_pbc(a: [f64; 3], b: [f64; 3], cell: &Cell) -> f64 {
+    let delta = get_vector_pbc(a, b, cell);
     let distance = (delta[0].powi(2) + delta[1].powi(2) + delta[2].powi(2)).sqrt();
     distance
 }
@@ -51,7 +48,7 @@ pub fn get_distance_pbc(a: [f64; 3], b: [f64; 3], cell: &Vec<f64>) -> f64 {
 /// Compute the angle of H-O-H, using the law of cosines.
 /// Return the angle in Rad.
 /// considering the periodic boundary condition.
-pub fn get_angle(a: [f64; 3], o: [f64; 3], b: [f64; 3], cell: &Vec<f64>) -> f64 {
+pub fn get_angle(a: [f64; 3], o: [f64; 3], b: [f64; 3], cell: &Cell) -> f64 {
     let r_oa = get_distance_pbc(o, a, &cell);
     let r_ob = get_distance_pbc(o, b, &cell);
     let r_ab = get_distance_pbc(a, b, &cell);
@@ -60,6 +57,32 @@ pub fn get_angle(a: [f64; 3], o: [f64; 3], b: [f64; 3], cell: &Vec<f64>) -> f64
     rad
 }
 
-pub fn unwrap(system: &Vec<Frame>) -> Result<(), Box<dyn Error>> {
-    Ok(())
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distance_orthogonal() {
+        let cell = Cell::from_lengths([10.0, 10.0, 10.0]);
+        let d = get_distance_pbc([0.5, 0.0, 0.0], [9.5, 0.0, 0.0], &cell);
+        assert!((d - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn distance_triclinic() {
+        //  hexagonal cell, a = b = 4, gamma = 120 deg
+        let cell = Cell::new([
+            [4.0, 0.0, 0.0],
+            [-2.0, 2.0 * 3.0_f64.sqrt(), 0.0],
+            [0.0, 0.0, 10.0],
+        ]);
+        let a = cell.to_cartesian([0.05, 0.05, 0.0]);
+        let b = cell.to_cartesian([0.95, 0.95, 0.0]);
+        //  the two points are 0.1*(a+b) apart through the corner, |a+b| = 4
+        let d = get_distance_pbc(a, b, &cell);
+        assert!((d - 0.4).abs() < 1e-12);
+        let s = cell.to_fractional(cell.to_cartesian([0.3, 0.6, 0.9]));
+        assert!((s[0] - 0.3).abs() < 1e-12 && (s[1] - 0.6).abs() < 1e-12 && (s[2] - 0.9).abs() < 1e-12);
+        assert!((cell.volume() - 80.0 * 3.0_f64.sqrt()).abs() < 1e-9);
+    }
+}
@@ -4,124 +4,225 @@
 //! D = \lim_{t\rightarrow \infinity} \frac{1}{6Nt}
 //!     \left\angle \sum_{j=1}^{N} \[ r_j(t)-r_j(0) \]^2 \right\angle
 //!
-//! Taskoption should be #element #startstep #stopstep #step of step.
-//! 
-//! An example command to run this process is 
-//! ```
-//! execfile ./XDATCAR vasp/xdatcar 1 2000 1 msd O xyz 1 2000 1 ./msd.out
+//! Taskoption should be #element #direction #startstep #stopstep #step of step,
+//! and optionally #dt (ps between two frames) #fit_start #fit_stop.
+//! With #element = water the msd of the center of mass of the water molecules
+//! is computed, and the word nodrift removes the drift of the center of mass
+//! of the system (see task::unwrap::remove_com_drift).
+//!
+//! The msd of all the lags is computed by FFT (see task::fft) in O(N log N),
+//! and D is fitted (least squares) from the msd between fit_start and
+//! fit_stop (in frames, from stopstep/5 to stopstep-1 by default).
+//!
+//! An example command to run this process is
+//! ```bash
+//! execfile --in ./XDATCAR --infmt vasp/xdatcar --task msd --taskopt "O xyz 1 2000 1 0.01" --out ./msd.out
 //! ```
 
-use crate::{Atom, Frame};
-use crate::task::get_distance_pbc;
-use std::error::Error;
+use crate::Frame;
+use crate::task::element::mass;
+use crate::task::topology::{Species, Topology, RCUT_COV};
+use crate::task::unwrap::{remove_com_drift, unwrap, unwrap_molecules};
+use crate::task::fft::{msd_fft, Correlator};
+use crate::error::{parse_opt, Error};
+use std::collections::HashMap;
 use std::fs;
-use std::fs::OpenOptions;
 use std::io::Write;
 
 /// Compute the average msd
 /// 
-/// msdopt = vec!["elementA", "direction_type", "stepstart", "d step", "max step"];
+/// msdopt = vec!["elementA", "direction_type", "stepstart", "stepstop", "dstep", ("dt", "fit_start", "fit_stop")];
 /// 
-/// "direction_type" should be one of ['xyz','xy','xz','yz','x','y','z']
-/// 
-/// To be done: get the real coordination before the msd loop
-pub fn msd(system: &mut Vec<Frame>, msdopt: &Vec<&str>, output: &str) -> Result<(), Box<dyn Error>> {
-    // load task option
-    let type_name: &str = &msdopt[0];                     // "elementA"
-    let direction: Vec<usize> = match &msdopt[1] as &str {
-        "xyz" => vec![0,1,2],
-        "xy"  => vec![0,1],
-        "xz"  => vec![0,2],
-        "yz"  => vec![1,2],
-        "x"   => vec![0],
-        "y"   => vec![1],
-        "z"   => vec![2],
-        _     => panic!("Wrong direction_type, please make sure direction_type 
-                         is in ['xyz','xy','xz','yz','x','y','z']!"),
+/// "direction_type" should be one of ['xyz','xy','xz','yz','x','y','z'],
+/// the msd of each direction is written as well if there are more than one.
+pub fn msd(system: &mut Vec<Frame>, msdopt: &Vec<&str>, output: &str) -> Result<(), Error> {
+    // load task option, "nodrift" can be put anywhere after the direction
+    let remove_drift: bool = msdopt.iter().skip(2).any(|x| *x == "nodrift");
+    let msdopt: Vec<&str> = msdopt.iter().cloned().filter(|x| *x != "nodrift").collect();
+    let msdopt: &Vec<&str> = &msdopt;
+    let type_name: String = parse_opt(msdopt, 0, "elementA")?;      // "elementA"
+    let type_name: &str = &type_name;
+    let direction: Vec<usize> = parse_direction(&parse_opt::<String>(msdopt, 1, "direction_type")?)?;
+    let stepstart: i32 = parse_opt(msdopt, 2, "stepstart")?;  // start of frame_step
+    let stepstop: i32 = parse_opt(msdopt, 3, "stepstop")?;    // end of frame_step
+    let dstep: i32 = parse_opt(msdopt, 4, "dstep")?;          // step of frame_step
+    let dt: Option<f64> = match msdopt.get(5) {               // time (ps) between two frames
+        Some(_) => Some(parse_opt(msdopt, 5, "dt")?),
+        None => None,
     };
-    let stepstart: i32 = msdopt[2].parse::<i32>().unwrap();  // start of frame_step
-    let stepstop: i32 = msdopt[3].parse::<i32>().unwrap();   // end of frame_step
-    let dstep: i32 = msdopt[4].parse::<i32>().unwrap();      // step of frame_step
+    if system.is_empty() {
+        return Err(Error::InvalidOption("no frame is selected, please check frameopt".to_string()));
+    }
+    if stepstart < 1 || dstep < 1 || stepstop as usize > system.len() {
+        return Err(Error::InvalidOption(format!(
+            "msd needs 1 <= stepstart, 1 <= dstep and stepstop <= {} (number of frames)",
+            system.len())));
+    }
     // load system information
     //let cell: &Vec<f64> = &system[0].cell;                   // [Lx, Ly, Lz]
     //let cutoff1: f64 = 0.7*cell[0]  ;                        // 
     //let _cutoff2: f64 = 0.25*cell[0] ;                       // 
-    let atom_type: &Vec<String> = &system[0].atom_type;      // ["elementA", "elementB"]
-    let atom_numb: &Vec<i32> = &system[0].atom_numb;         // ["# of eleA", "# of eleB"]
-    let natom: i32 = atom_numb.iter().sum::<i32>();          // number of atom in one frame
-    let mut n: i32 = 0;                                      // number of elementA
-    
-    for i in 0..atom_type.len() {
-        if &atom_type[i] == type_name {
-            n = atom_numb[i];
-        }
-    }
-    // It seems like for nvt only? because the average number is divided at the end.
-
-    // unwrap the coordination
-    let mut sumbox: Vec< [i32;3] >  =                        // record the box
-        vec![ [0; 3] ; natom as usize ];
-        // index: sumbox[atom_idx][xyz]
+    let centers: Vec<Vec<[f64; 3]>> = trajectories(system, type_name, remove_drift)?;
+    let n: usize = centers.len();
 
-
-    for k in 1..(system.len() as usize) {                      // loop for start frames
-        let mut dr: [f64;3] = [0.0;3];                       // tmp variable
-        for i in 0..natom as usize {                           // loop for atoms
-            
-            //println!("{:?}", system[k].atom[i].coordination[0]);
-            for j in 0..3 {                                  // loop for xyz
-
-                dr[j] = system[k].atom[i].coordination[j] - system[k-1].atom[i].coordination[j] + (sumbox[i][j] as f64 )*system[0].cell[j];
-                if dr[j] > 0.5*system[0].cell[j] {
-                    sumbox[i][j] -= 1;
-                } else if dr[j] < -0.5*system[0].cell[j] {
-                    sumbox[i][j] += 1;
-                }
-                system[k].atom[i].coordination[j] += (sumbox[i][j] as f64 )*system[0].cell[j];
+    // main loop for msd
+    //  msd of each direction for all the lags, by FFT, summed over atoms
+    let nframe: usize = system.len();
+    let correlator = Correlator::new(nframe);
+    let mut msd_direction: Vec<Vec<f64>> = vec![vec![0.0; nframe]; direction.len()];
+    for center in centers.iter() {
+        for (d, &j) in direction.iter().enumerate() {
+            let x: Vec<f64> = center.iter().map(|r| r[j]).collect();
+            for (m, value) in msd_fft(&x, &correlator).into_iter().enumerate() {
+                msd_direction[d][m] += value / n as f64;
             }
         }
     }
-    println!("converted!");
+    let msd_total: Vec<f64> = (0..nframe).map(|m| msd_direction.iter().map(|x| x[m]).sum()).collect();
 
-    // main loop for msd
-    let mut o = fs::File::create(&output).unwrap();
+    //  ------linear fit of the diffusive regime------
+    let fit_start: usize = match msdopt.get(6) {
+        Some(_) => parse_opt(msdopt, 6, "fit_start")?,
+        None => (stepstop as usize / 5).max(1),
+    };
+    let fit_stop: usize = match msdopt.get(7) {
+        Some(_) => parse_opt(msdopt, 7, "fit_stop")?,
+        None => stepstop as usize - 1,
+    };
+    if fit_stop >= nframe || fit_start + 2 > fit_stop {
+        return Err(Error::InvalidOption(format!(
+            "msd needs fit_start + 2 <= fit_stop < {} (number of frames)", nframe)));
+    }
+    let lag_time = |m: usize| -> f64 { m as f64 * dt.unwrap_or(1.0) };
+    let unit: &str = if dt.is_some() { "A^2/ps" } else { "A^2/frame" };
+    let mut o = fs::File::create(&output)?;
     for dk in (stepstart..stepstop).filter(|x| ((x-stepstart)%dstep == 0) ) { // loop for frame length
+        let mut line: String = match dt {
+            Some(_) => format!("{:.4}  {:.8}", lag_time(dk as usize), msd_total[dk as usize]),
+            None => format!("{:.4}  {:.8}", dk, msd_total[dk as usize]),
+        };
+        if direction.len() > 1 {
+            for x in msd_direction.iter() {
+                line += &format!("  {:.8}", x[dk as usize]);
+            }
+        }
+        o.write_all((line + "\n").as_bytes())?;
+    }
+    let names: Vec<String> = direction.iter().map(|&j| ["x", "y", "z"][j].to_string()).collect();
+    let mut fits: Vec<(String, &Vec<f64>, f64)> = vec![(names.concat(), &msd_total, direction.len() as f64)];
+    if direction.len() > 1 {
+        for (d, name) in names.iter().enumerate() {
+            fits.push((name.clone(), &msd_direction[d], 1.0));
+        }
+    }
+    for (name, msd, dimension) in fits {
+        let t: Vec<f64> = (fit_start..=fit_stop).map(lag_time).collect();
+        let (slope, error) = linear_fit(&t, &msd[fit_start..=fit_stop]);
+        //  msd = 2 d D t for d dimensions
+        let (diffusion, error) = (slope / (2.0 * dimension), error / (2.0 * dimension));
+        println!("D_{} = {:.6e} +- {:.6e} {}", name, diffusion, error, unit);
+        o.write_all(format!("#D_{} {:.8e} +- {:.8e} {}\n", name, diffusion, error, unit).as_bytes())?;
+    }
+    if dt.is_some() {
+        println!("(1 A^2/ps = 1e-4 cm^2/s)");
+    }
 
-        println!("Processing for dk = {}, please wait...", dk);
-        let mut count:i32 = 0;                       // count the loop times
-        let mut total_sd: f64 = 0.0;                 // recoord total_sd in interval dk
-
-        let frame_number: usize = system.len() - dk as usize;
-
-
-        for k in 0..(system.len()-dk as usize) {                      // loop for start frames
-
-            let mut sd_oneframe: f64 = 0.0;
-
-            for i in 0..natom as usize {                           // loop for atoms
+    Ok(())
+}
 
-                let mut dr: [f64;3] = [0.0;3];
+/// The index of the directions of a direction_type, e.g. [0, 1] for "xy".
+pub fn parse_direction(direction_type: &str) -> Result<Vec<usize>, Error> {
+    match direction_type {
+        "xyz" => Ok(vec![0,1,2]),
+        "xy"  => Ok(vec![0,1]),
+        "xz"  => Ok(vec![0,2]),
+        "yz"  => Ok(vec![1,2]),
+        "x"   => Ok(vec![0]),
+        "y"   => Ok(vec![1]),
+        "z"   => Ok(vec![2]),
+        _     => Err(Error::InvalidOption("Wrong direction_type, please make sure direction_type \
+                         is in ['xyz','xy','xz','yz','x','y','z']!".to_string())),
+    }
+}
 
-                if &system[k].atom[i].type_name == type_name {
-                    
-                    for &j in direction.iter() {  // loop for x, y, z
+/// Unwrap the system and return the trajectory ([atom][frame]) of each atom of
+/// the element, or of the center of mass of each water molecule if type_name is "water".
+///
+/// If remove_drift, the drift of the center of mass of the system is removed.
+pub fn trajectories(system: &mut Vec<Frame>, type_name: &str, remove_drift: bool) -> Result<Vec<Vec<[f64; 3]>>, Error> {
+    if system.is_empty() {
+        return Err(Error::InvalidOption("no frame is selected, please check frameopt".to_string()));
+    }
+    let natom: usize = system[0].atom.len();                // number of atom in one frame
+    if system.iter().any(|x| x.atom.len() != natom) {
+        return Err(Error::Format("the same number of atoms is needed in all the frames".to_string()));
+    }
 
-                        dr[j] = system[k+dk as usize].atom[i].coordination[j] 
-                        - system[k].atom[i].coordination[j];
+    // unwrap the coordination, see task::unwrap
+    if type_name == "water" {
+        unwrap_molecules(system, &["O"], RCUT_COV)?;
+    } else {
+        unwrap(system)?;
+    }
+    if remove_drift {
+        remove_com_drift(system)?;
+    }
+    println!("converted!");
 
-                    }
-                }
-                sd_oneframe += dr[0].powi(2) + dr[1].powi(2) + dr[2].powi(2);
+    //  groups of (position in frame.atom, weight), the trajectory of their center is returned,
+    //  one atom for an element, or the center of mass of each water molecule
+    let mut groups: Vec<Vec<(usize, f64)>> = vec![];
+    if type_name == "water" {
+        let position: HashMap<i32, usize> =
+            system[0].atom.iter().enumerate().map(|(i, x)| (x.index, i)).collect();
+        let topo = Topology::new(&system[0], &["O"], RCUT_COV)?;
+        for molecule in topo.molecules.iter().filter(|m| m.species == Species::Water) {
+            let mut group: Vec<(usize, f64)> = vec![];
+            for atom in [molecule.center].iter().chain(molecule.hydrogen.iter()) {
+                group.push((position[&atom.index], mass(&atom.type_name)?));
+            }
+            groups.push(group);
+        }
+    } else {
+        for (i, atom) in system[0].atom.iter().enumerate() {
+            if atom.type_name == type_name {
+                groups.push(vec![(i, 1.0)]);
             }
-            total_sd += sd_oneframe/n as f64;
-            count += 1;
         }
-        let msd = total_sd / count as f64;
-        //println!("for dk = {}, total_sd = {}, count = {}, msd = {}", dk, total_sd, count, msd);
-        o.write((format!("{:.4}  {:.8}", dk, msd)+"\n").as_bytes() )
-            .expect("write msd to file failed");
     }
-
-    Ok(())
+    if groups.is_empty() {
+        return Err(Error::InvalidOption(format!("no atom of element {} is found", type_name)));
+    }
+    let mut centers: Vec<Vec<[f64; 3]>> = vec![];
+    for group in groups.iter() {
+        let total: f64 = group.iter().map(|x| x.1).sum();
+        let center: Vec<[f64; 3]> = system
+            .iter()
+            .map(|frame| {
+                let mut r: [f64; 3] = [0.0; 3];
+                for j in 0..3 {
+                    r[j] = group.iter().map(|&(i, w)| w * frame.atom[i].coordination[j]).sum::<f64>() / total;
+                }
+                r
+            })
+            .collect();
+        centers.push(center);
+    }
+    Ok(centers)
 }
 
+/// Least squares fit of y = a + b*x, return b and its standard error.
+///
+/// The msd of different lags are correlated, so the error is a lower bound.
+pub fn linear_fit(x: &[f64], y: &[f64]) -> (f64, f64) {
+    let n: f64 = x.len() as f64;
+    let x_avg: f64 = x.iter().sum::<f64>() / n;
+    let y_avg: f64 = y.iter().sum::<f64>() / n;
+    let sxx: f64 = x.iter().map(|v| (v - x_avg).powi(2)).sum();
+    let sxy: f64 = x.iter().zip(y).map(|(u, v)| (u - x_avg) * (v - y_avg)).sum();
+    let slope: f64 = sxy / sxx;
+    let intercept: f64 = y_avg - slope * x_avg;
+    let ssr: f64 = x.iter().zip(y).map(|(u, v)| (v - intercept - slope * u).powi(2)).sum();
+    let error: f64 = (ssr / (n - 2.0) / sxx).sqrt();
+    (slope, error)
+}
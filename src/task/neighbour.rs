@@ -0,0 +1,192 @@
+//! Neighbour search with a cell list, considering the periodic boundary condition.
+//!
+//! The cell is divided into bins whose width (perpendicular to the faces,
+//! so triclinic cells are fine) is at least the cutoff, then the neighbours
+//! of a point can only be in the 27 bins around it. Building the list is
+//! O(N) and each query is O(1) for a uniform system, instead of O(N).
+//!
+//! A typical use is
+//! ```rust,ignore
+//! let list = CellList::new(coord_O.iter().map(|x| x.coordination).collect(), &frame.cell, 3.5);
+//! for (j, d) in list.within(atom.coordination, 3.5) { ... }
+//! ```
+
+use crate::task::get_distance_pbc;
+use crate::Cell;
+
+pub struct CellList {
+    cell       : Cell,
+    points     : Vec<[f64; 3]>,
+    nbin       : [usize; 3],          // number of bins along a, b, c
+    bins       : Vec<Vec<usize>>,     // index of the points in each bin
+    pub cutoff : f64,
+}
+
+impl CellList {
+    /// Put the points (Cartesian) into bins for neighbour search within cutoff.
+    pub fn new(points: Vec<[f64; 3]>, cell: &Cell, cutoff: f64) -> CellList {
+        //  width of the cell perpendicular to the face (b, c) is V / |b x c|, etc.
+        let m = &cell.matrix;
+        let volume = cell.volume();
+        let mut nbin = [1; 3];
+        for i in 0..3 {
+            let (u, v) = (m[(i + 1) % 3], m[(i + 2) % 3]);
+            let cross = [
+                u[1] * v[2] - u[2] * v[1],
+                u[2] * v[0] - u[0] * v[2],
+                u[0] * v[1] - u[1] * v[0],
+            ];
+            let area = (cross[0].powi(2) + cross[1].powi(2) + cross[2].powi(2)).sqrt();
+            let width = volume / area;
+            if cutoff > 0.0 {
+                nbin[i] = ((width / cutoff).floor() as usize).max(1);
+            }
+        }
+        let mut list = CellList {
+            cell: cell.clone(),
+            points: vec![],
+            nbin,
+            bins: vec![vec![]; nbin[0] * nbin[1] * nbin[2]],
+            cutoff,
+        };
+        for (i, r) in points.iter().enumerate() {
+            let b = list.bin_of(*r);
+            list.bins[b].push(i);
+        }
+        list.points = points;
+        list
+    }
+
+    /// Number of points in the list.
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Position of the i-th point.
+    pub fn point(&self, i: usize) -> [f64; 3] {
+        self.points[i]
+    }
+
+    /// bin index along a, b, c of a Cartesian position
+    fn bin_xyz(&self, r: [f64; 3]) -> [usize; 3] {
+        let o = self.cell.origin;
+        let s = self.cell.to_fractional([r[0] - o[0], r[1] - o[1], r[2] - o[2]]);
+        let mut b = [0; 3];
+        for i in 0..3 {
+            let f = s[i] - s[i].floor();
+            b[i] = ((f * self.nbin[i] as f64) as usize).min(self.nbin[i] - 1);
+        }
+        b
+    }
+
+    fn bin_of(&self, r: [f64; 3]) -> usize {
+        let b = self.bin_xyz(r);
+        (b[0] * self.nbin[1] + b[1]) * self.nbin[2] + b[2]
+    }
+
+    /// The bins around (and including) the bin of r, each bin only once.
+    fn neighbour_bins(&self, r: [f64; 3]) -> Vec<usize> {
+        let b = self.bin_xyz(r);
+        let mut around: Vec<Vec<usize>> = vec![];
+        for (&bi, &n) in b.iter().zip(self.nbin.iter()) {
+            if n < 3 {
+                around.push((0..n).collect());
+            } else {
+                around.push(vec![(bi + n - 1) % n, bi, (bi + 1) % n]);
+            }
+        }
+        let mut bins: Vec<usize> = vec![];
+        for i in around[0].iter() {
+            for j in around[1].iter() {
+                for k in around[2].iter() {
+                    bins.push((i * self.nbin[1] + j) * self.nbin[2] + k);
+                }
+            }
+        }
+        bins
+    }
+
+    /// (index, distance) of the points within rcut of center, ordered by index.
+    ///
+    /// rcut should not be larger than the cutoff of the list.
+    pub fn within(&self, center: [f64; 3], rcut: f64) -> Vec<(usize, f64)> {
+        let mut found: Vec<(usize, f64)> = vec![];
+        for b in self.neighbour_bins(center) {
+            for &j in self.bins[b].iter() {
+                let d = get_distance_pbc(center, self.points[j], &self.cell);
+                if d < rcut {
+                    found.push((j, d));
+                }
+            }
+        }
+        found.sort_by_key(|x| x.0);
+        found
+    }
+
+    /// The k nearest points of center within the cutoff, ordered by distance.
+    ///
+    /// Points not farther than rmin (e.g. the center itself) are skipped,
+    /// fewer than k points are returned if there are not enough.
+    pub fn nearest(&self, center: [f64; 3], k: usize, rmin: f64) -> Vec<(usize, f64)> {
+        let mut found: Vec<(usize, f64)> = self
+            .within(center, self.cutoff)
+            .into_iter()
+            .filter(|x| x.1 > rmin)
+            .collect();
+        found.sort_by(|a, b| (a.1).partial_cmp(&b.1).unwrap().then(a.0.cmp(&b.0)));
+        found.truncate(k);
+        found
+    }
+
+    /// All the pairs (i, j, distance) with i < j within the cutoff.
+    pub fn pairs(&self) -> Vec<(usize, usize, f64)> {
+        let mut pairs: Vec<(usize, usize, f64)> = vec![];
+        for i in 0..self.points.len() {
+            for (j, d) in self.within(self.points[i], self.cutoff) {
+                if j > i {
+                    pairs.push((i, j, d));
+                }
+            }
+        }
+        pairs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_as_brute_force() {
+        let cell = Cell::new([[9.0, 0.0, 0.0], [3.0, 8.0, 0.0], [-1.0, 2.0, 10.0]]);
+        let mut points: Vec<[f64; 3]> = vec![];
+        //  a simple deterministic scatter of points
+        for i in 0..200 {
+            let s = [
+                (i as f64 * 0.618034) % 1.0,
+                (i as f64 * 0.414214) % 1.0,
+                (i as f64 * 0.732051) % 1.0,
+            ];
+            points.push(cell.to_cartesian(s));
+        }
+        let list = CellList::new(points.clone(), &cell, 2.5);
+        for i in 0..points.len() {
+            let brute: Vec<usize> = (0..points.len())
+                .filter(|&j| get_distance_pbc(points[i], points[j], &cell) < 2.5)
+                .collect();
+            let found: Vec<usize> = list.within(points[i], 2.5).iter().map(|x| x.0).collect();
+            assert_eq!(brute, found);
+        }
+        let npair = list.pairs().len();
+        let brute_pair = (0..points.len())
+            .map(|i| (i + 1..points.len())
+                .filter(|&j| get_distance_pbc(points[i], points[j], &cell) < 2.5)
+                .count())
+            .sum::<usize>();
+        assert_eq!(npair, brute_pair);
+    }
+}
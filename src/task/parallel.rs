@@ -0,0 +1,56 @@
+//! Run a per-frame function on many frames at the same time.
+//!
+//! Frames are read in batches (the reading itself is serial), each batch is
+//! mapped in parallel with rayon, and the results are reduced one by one in
+//! the order of the frames, so the output is the same as a serial run no
+//! matter how many threads are used (set by `--threads`).
+
+use crate::error::Error;
+use crate::Frame;
+use rayon::prelude::*;
+
+/// Apply map to each frame in parallel and give the results to reduce in frame order.
+///
+/// If a frame can't be read or mapped, the frames before it are still
+/// reduced and then the error is returned, as a serial loop would do.
+pub fn map_frames<I, T, F, R>(system: I, map: F, mut reduce: R) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+    T: Send,
+    F: Fn(&Frame) -> Result<T, Error> + Sync,
+    R: FnMut(&Frame, T) -> Result<(), Error>,
+{
+    //  a few frames per thread, so that only a batch is kept in memory
+    let batch_size: usize = rayon::current_num_threads() * 4;
+    let mut system = system;
+    loop {
+        let mut batch: Vec<Frame> = vec![];
+        let mut read_error: Option<Error> = None;
+        for frame in system.by_ref() {
+            match frame {
+                Ok(frame) => batch.push(frame),
+                Err(e) => {
+                    read_error = Some(e);
+                    break;
+                }
+            }
+            if batch.len() == batch_size {
+                break;
+            }
+        }
+        if batch.is_empty() && read_error.is_none() {
+            return Ok(());
+        }
+
+        let results: Vec<Result<T, Error>> = batch.par_iter().map(&map).collect();
+        for (frame, result) in batch.iter().zip(results) {
+            reduce(frame, result?)?;
+        }
+        if let Some(e) = read_error {
+            return Err(e);
+        }
+        if batch.len() < batch_size {
+            return Ok(());
+        }
+    }
+}
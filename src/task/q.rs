@@ -1,94 +1,366 @@
-//! This module contains the function to compute the tetrahedral order parameter q.
+//! This module contains the function to compute the tetrahedral order parameter q,
+//! the local translational order parameter S_k of Chau and Hardwick (Mol. Phys.
+//! 93, 511 (1998)) and the translational order parameter t of Errington and
+//! Debenedetti (Nature 409, 318 (2001)).
 //!
+//! With the n nearest neighbours (4 O by default) of each center atom (O),
+//! - q   = 1 - 9 / (2 n (n-1)) sum_{j<k} (cos(psi_jk) + 1/3)^2, which is the
+//!   usual 1 - 3/8 sum (cos + 1/3)^2 for n = 4, 1 for a perfect tetrahedron
+//!   and 0 on average for random neighbours,
+//! - S_k = 1 / (3 n) sum_k (r_k - r_mean)^2 / r_mean^2, 0 for neighbours at
+//!   the same distance.
+//!
+//! qopt is optional, in the form of key=value
+//! - center=O, the element of the center atoms
+//! - neighbour=O, the element of the neighbours
+//! - numb=4, the number of neighbours
+//! - rcut=5.0, the neighbours are searched within rcut (Angstrom), a center
+//!   atom with fewer neighbours is skipped and counted
+//! - hist=min,max,bins, the range of the distribution, 0,1,100 for q and
+//!   0,0.02,100 for S_k
+//!
+//! t is a property of the whole frame instead of each center atom: with the
+//! reduced distance xi = r rho^(1/3) of the atoms of one element (rho their
+//! number density), t = 1/xi_c int_0^xi_c |g(xi) - 1| dxi, which is 0 for an
+//! ideal gas. Its option is "element=O xi=2.843 bins=200" (all optional), the
+//! cutoff xi_c rho^(-1/3) should be within half of the cell.
+//!
+//! e.g.
+//! ```bash
+//! execfile --in ./a.xdatcar --infmt vasp/xdatcar --task q --taskopt "numb=4 hist=-0.5,1,150" --out ./q.dat
+//! execfile --in ./a.xdatcar --infmt vasp/xdatcar --task sk --out ./sk.dat
+//! execfile --in ./a.xdatcar --infmt vasp/xdatcar --task translational --taskopt "xi=1.9" --out ./t.dat
+//! ```
 
-use crate::task::cov::find_cov_oneatom;
-use crate::task::{get_angle, get_distance_pbc};
-use crate::{Atom, Frame};
-use std::error::Error;
+use crate::task::get_vector_pbc;
+use crate::task::neighbour::CellList;
+use crate::task::parallel::map_frames;
+use crate::task::stats::{BlockAverage, Histogram};
+use crate::{Atom, Cell, Frame};
+use crate::error::Error;
+use std::f64::consts::PI;
 use std::fs;
-use std::fs::OpenOptions;
 use std::io::Write;
 
-/// Find the nearest four O atoms for one O atom.
-pub fn find_neighbour<'a>(
-    atom_oc: &Atom,  // center atom O
-    coord_O: &'a Vec<&Atom>, 
-    cell: &Vec<f64>
-) -> Result<Vec<&'a Atom>, Box<dyn Error>> {
-    let mut neighbour: Vec<&Atom> = vec![];
-    let mut distance: Vec<(&Atom, f64)> = vec![];
-    for atom_o in coord_O.iter() {
-        let d = get_distance_pbc(atom_oc.coordination, atom_o.coordination, &cell);
-        if d > 0.1 && d <= 5.0 {
-            distance.push( (&atom_o, d) );
+/// The neighbours of the center atoms, see the module document.
+#[derive(Debug, Clone)]
+pub struct Shell {
+    pub center    : String,
+    pub neighbour : String,
+    pub numb      : usize,
+    pub rcut      : f64,
+}
+
+impl Default for Shell {
+    fn default() -> Shell {
+        Shell { center: "O".to_string(), neighbour: "O".to_string(), numb: 4, rcut: 5.0 }
+    }
+}
+
+impl Shell {
+    /// Read center=, neighbour=, numb= and rcut= from the task option,
+    /// the other options are returned as (key, value) for the task.
+    pub fn from_opt<'a>(opt: &[&'a str]) -> Result<(Shell, Vec<(&'a str, &'a str)>), Error> {
+        let mut shell = Shell::default();
+        let mut others: Vec<(&str, &str)> = vec![];
+        for word in opt.iter() {
+            let (key, value) = match word.split_once('=') {
+                Some(kv) => kv,
+                None => (*word, ""),
+            };
+            let wrong = || Error::InvalidOption(format!("can't parse {} from \"{}\"", key, value));
+            match key {
+                "center" => shell.center = value.to_string(),
+                "neighbour" => shell.neighbour = value.to_string(),
+                "numb" => shell.numb = value.parse().map_err(|_| wrong())?,
+                "rcut" => shell.rcut = value.parse().map_err(|_| wrong())?,
+                _ => others.push((key, value)),
+            }
         }
+        if shell.numb < 2 || shell.rcut <= 0.0 {
+            return Err(Error::InvalidOption(format!(
+                "numb of neighbours should be at least 2 and rcut positive, not {} {}", shell.numb, shell.rcut)));
+        }
+        Ok((shell, others))
     }
-    // println!("{:?}", distance);
-    distance.sort_by(|a, b| (a.1).partial_cmp(&b.1).unwrap());
-    // println!("{:?}", distance);
-    for i in 0..4 {
-        neighbour.push(distance[i].0)
+}
+
+/// Find the nearest numb atoms for one center atom.
+///
+/// list is the cell list of coord, an error is returned if there are fewer
+/// than numb atoms within the cutoff of the list.
+pub fn find_neighbour<'a>(
+    atom_oc: &Atom,  // center atom
+    coord: &Vec<&'a Atom>,
+    list: &CellList,
+    numb: usize,
+) -> Result<Vec<&'a Atom>, Error> {
+    let distance: Vec<(usize, f64)> = list.nearest(atom_oc.coordination, numb, 0.1);
+    if distance.len() < numb {
+        return Err(Error::Topology(format!(
+            "only {} atoms within {} A of atom {}, expect at least {}", distance.len(), list.cutoff, atom_oc.index, numb)));
     }
-    //println!("atom {} has {} neighbours", atom_oc.index,neighbour.len());
-    if neighbour.len() < 4 {
-        panic!("# of neighbours is less than 4!")
+    Ok(distance.iter().map(|x| coord[x.0]).collect())
+}
+
+/// The vectors from each center atom to its neighbours in one frame,
+/// None for a center atom without enough neighbours.
+pub fn neighbour_vectors<'a>(frame: &'a Frame, shell: &Shell) -> Vec<(&'a Atom, Option<Vec<[f64; 3]>>)> {
+    let cell: &Cell = &frame.cell;
+    let coord: Vec<&Atom> = frame.atom.iter().filter(|x| x.type_name == shell.neighbour).collect();
+    let list = CellList::new(coord.iter().map(|x| x.coordination).collect(), cell, shell.rcut);
+    frame.atom.iter()
+        .filter(|x| x.type_name == shell.center)  // use --typemap for lammps
+        .map(|atom_oc| {
+            let vectors = find_neighbour(atom_oc, &coord, &list, shell.numb).ok().map(|neighbour| {
+                neighbour.iter().map(|x| get_vector_pbc(atom_oc.coordination, x.coordination, cell)).collect()
+            });
+            (atom_oc, vectors)
+        })
+        .collect()
+}
+
+/// q from the vectors to the neighbours.
+pub fn tetrahedral(vectors: &[[f64; 3]]) -> f64 {
+    let n: usize = vectors.len();
+    let norm: Vec<f64> = vectors.iter().map(|v| (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()).collect();
+    let mut answer: f64 = 0.0;
+    for i in 0..n - 1 {
+        for j in (i + 1)..n {
+            let a = vectors[i];
+            let b = vectors[j];
+            let cos: f64 = (a[0] * b[0] + a[1] * b[1] + a[2] * b[2]) / (norm[i] * norm[j]);
+            answer += (cos + 1.0 / 3.0).powi(2);
+        }
     }
-    Ok(neighbour)
-}
-
-
-/// Compute the q in one frame
-/// and the answer is stored
-pub fn q_oneframe(frame: &Frame, output: &str) -> Result<(f64), Box<dyn Error>> {
-        
-    let mut o = OpenOptions::new()
-        .append(true)
-        .open(output)
-        .expect("cannot open file");
-    //  ------Collect the information the frame------
-    let cell: &Vec<f64> = &frame.cell;
-    let mut numb_O: i32 = 0;
-    let mut coord_O: Vec<&Atom> = vec![];
-    for i in frame.atom.iter() {
-        if i.type_name == "O" || i.type_name == "1" {  // O,H for vasp and 1,2 for lammps
-            coord_O.push(i);
-            numb_O += 1;
+    1.0 - 9.0 / (2 * n * (n - 1)) as f64 * answer
+}
+
+/// S_k from the vectors to the neighbours.
+pub fn local_translational(vectors: &[[f64; 3]]) -> f64 {
+    let n: f64 = vectors.len() as f64;
+    let r: Vec<f64> = vectors.iter().map(|v| (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt()).collect();
+    let mean: f64 = r.iter().sum::<f64>() / n;
+    r.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (3.0 * n * mean * mean)
+}
+
+/// Compute the q of each center atom in one frame as (index, q),
+/// in the order of the atoms, None for an atom without enough neighbours.
+pub fn q_oneframe(frame: &Frame, shell: &Shell) -> Result<Vec<(i32, Option<f64>)>, Error> {
+    Ok(neighbour_vectors(frame, shell).into_iter()
+        .map(|(atom, vectors)| (atom.index, vectors.map(|v| tetrahedral(&v))))
+        .collect())
+}
+
+/// Compute S_k of each center atom in one frame, the same as q_oneframe.
+pub fn sk_oneframe(frame: &Frame, shell: &Shell) -> Result<Vec<(i32, Option<f64>)>, Error> {
+    Ok(neighbour_vectors(frame, shell).into_iter()
+        .map(|(atom, vectors)| (atom.index, vectors.map(|v| local_translational(&v))))
+        .collect())
+}
+
+/// Run q or S_k (name) for all the frames, see write_order_parameter().
+fn order_parameter<I, F>(system: I, opt: &Vec<&str>, output: &str, name: &str, default: (f64, f64, usize), oneframe: F) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+    F: Fn(&Frame, &Shell) -> Result<Vec<(i32, Option<f64>)>, Error> + Sync,
+{
+    let (shell, others) = Shell::from_opt(opt)?;
+    println!("neighbours: {:?}", shell);
+    let mut hist: Histogram = Histogram::new(default.0, default.1, default.2)?;
+    for (key, value) in others {
+        if key != "hist" {
+            return Err(Error::InvalidOption(format!(
+                "unknown {} option {}, should be center, neighbour, numb, rcut or hist", name, key)));
         }
+        hist = Histogram::parse(value, name)?;
     }
+    write_order_parameter(system, output, name, hist, |i| oneframe(i, &shell))
+}
 
-    // ---------------main loop-----------------------
-    let mut tmp_q: f64 = 0.0;
-    for atom_oc in coord_O.iter() {  //  atom_oc means center atom O
-        let neighbour: Vec<&Atom> = find_neighbour(atom_oc, &coord_O, &cell)?;
-        let mut answer: f64 = 1.0;
-        for i in 0..neighbour.len()-1 {
-            for j in (i+1)..neighbour.len() {
-                let tmp_rad: f64 = get_angle(neighbour[i].coordination,
-                    atom_oc.coordination,
-                    neighbour[j].coordination,
-                    &cell,
-                );
-                answer -= 0.375 * ( tmp_rad.cos() + 1.0/3.0 ).powi(2);
+/// Compute a per-molecule order parameter (name) for all the frames, oneframe gives
+/// (index, value) of each molecule in one frame, None for a molecule skipped.
+///
+/// Three files are written, output: "frame  index  value" of each molecule
+/// (grep an index for the time series of one molecule), output.frame: the
+/// average of each frame with the errors (see task::stats), output.dist: P(value).
+/// The frames are consumed one batch at a time and computed in parallel.
+pub fn write_order_parameter<I, F>(system: I, output: &str, name: &str, mut hist: Histogram, oneframe: F) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+    F: Fn(&Frame) -> Result<Vec<(i32, Option<f64>)>, Error> + Sync,
+{
+    let mut o = fs::File::create(&output)?;
+    let mut o_frame = fs::File::create(format!("{}.frame", output))?;
+    o.write_all(format!("# frame  index  {}\n", name).as_bytes())?;
+    o_frame.write_all(format!("# frame  {}\n", name).as_bytes())?;
+    let mut stats = BlockAverage::new(1);
+    let mut skipped: usize = 0;
+    map_frames(system, oneframe, |i, values| {
+        let mut sum: f64 = 0.0;
+        let mut numb: usize = 0;
+        for (index, value) in values.iter() {
+            match value {
+                Some(x) => {
+                    o.write_all(format!("{}  {}  {:.8}\n", i.frame_idx, index, x).as_bytes())?;
+                    hist.add(*x);
+                    sum += x;
+                    numb += 1;
+                }
+                None => skipped += 1,
             }
         }
-        o.write( (format!("{:.8}", answer) + "\n").as_bytes() )
-            .expect("write q to file failed");
-        tmp_q += answer;
+        if numb > 0 {
+            o_frame.write_all(format!("{}  {:.8}\n", i.frame_idx, sum / numb as f64).as_bytes())?;
+            stats.push(&[sum / numb as f64]);
+        }
+        Ok(())
+    })?;
+    if skipped > 0 {
+        println!("{} molecules are skipped, e.g. without enough neighbours", skipped);
     }
+    println!("Average {} is {:.8} +- {:.8}", name, stats.mean()[0], stats.error().1[0]);
+    stats.write(&mut o_frame, &[name.to_string()])?;
+    hist.write(&mut fs::File::create(format!("{}.dist", output))?, name)?;
+    Ok(())
+}
 
+/// Compute q of all the frames, see order_parameter() for the output files.
+pub fn q<I>(system: I, qopt: &Vec<&str>, output: &str) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
+    order_parameter(system, qopt, output, "q", (0.0, 1.0, 100), q_oneframe)
+}
+
+/// Compute S_k of all the frames, see order_parameter() for the output files.
+pub fn sk<I>(system: I, skopt: &Vec<&str>, output: &str) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
+    order_parameter(system, skopt, output, "S_k", (0.0, 0.02, 100), sk_oneframe)
+}
+
+/// g(xi) of the atoms of one element in numb_bins bins up to xi_c, with the
+/// reduced distance xi = r rho^(1/3), see the module document.
+pub fn g_xi(points: Vec<[f64; 3]>, cell: &Cell, xi_c: f64, numb_bins: usize) -> Result<Vec<f64>, Error> {
+    let n: f64 = points.len() as f64;
+    if points.len() < 2 {
+        return Err(Error::Topology("fewer than two atoms for t, use --typemap for lammps".to_string()));
+    }
+    let rho: f64 = n / cell.volume();
+    let rcut: f64 = xi_c / rho.cbrt();
+    //  the minimum image is only unique within half of the narrowest width of the cell
+    let m = &cell.matrix;
+    let width: f64 = (0..3).map(|i| {
+        let (u, v) = (m[(i + 1) % 3], m[(i + 2) % 3]);
+        let cross = [u[1] * v[2] - u[2] * v[1], u[2] * v[0] - u[0] * v[2], u[0] * v[1] - u[1] * v[0]];
+        cell.volume() / (cross[0].powi(2) + cross[1].powi(2) + cross[2].powi(2)).sqrt()
+    }).fold(f64::INFINITY, f64::min);
+    if rcut > 0.5 * width {
+        return Err(Error::InvalidOption(format!(
+            "xi={} of t is {:.3} A, larger than half of the cell ({:.3} A), use a smaller xi", xi_c, rcut, 0.5 * width)));
+    }
+    let dxi: f64 = xi_c / numb_bins as f64;
+    let mut count: Vec<f64> = vec![0.0; numb_bins];
+    for (_, _, d) in CellList::new(points, cell, rcut).pairs() {
+        count[((d * rho.cbrt() / dxi) as usize).min(numb_bins - 1)] += 2.0;
+    }
+    //  g(xi) = count / (n 4/3 pi (xi_2^3 - xi_1^3)), the density is 1 in the reduced units
+    Ok(count.iter().enumerate().map(|(k, c)| {
+        c / (n * 4.0 / 3.0 * PI * (((k + 1) as f64 * dxi).powi(3) - (k as f64 * dxi).powi(3)))
+    }).collect())
+}
 
-    Ok(tmp_q/(numb_O as f64))
+/// The translational order parameter t of Errington and Debenedetti from g(xi)
+/// in equal bins from 0 to xi_c.
+pub fn translational_order(g: &[f64]) -> f64 {
+    g.iter().map(|x| (x - 1.0).abs()).sum::<f64>() / g.len() as f64
 }
 
+/// Compute t of all the frames, "frame  t" of each frame and the average with
+/// the errors (see task::stats) are written into the output file. As t of a
+/// small cell is raised by the noise of g(xi), t of the g(xi) averaged over the
+/// frames is given at the end as well.
+///
+/// topt is "element=O xi=2.843 bins=200", all optional.
+pub fn translational<I>(system: I, topt: &Vec<&str>, output: &str) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
+    let mut element: &str = "O";
+    let mut xi_c: f64 = 2.843;
+    let mut numb_bins: usize = 200;
+    for word in topt.iter() {
+        let (key, value) = match word.split_once('=') {
+            Some(kv) => kv,
+            None => (*word, ""),
+        };
+        let wrong = || Error::InvalidOption(format!("can't parse {} from \"{}\"", key, value));
+        match key {
+            "element" => element = value,
+            "xi" => xi_c = value.parse().map_err(|_| wrong())?,
+            "bins" => numb_bins = value.parse().map_err(|_| wrong())?,
+            _ => return Err(Error::InvalidOption(format!(
+                "unknown translational option {}, should be element, xi or bins", key))),
+        }
+    }
+    if xi_c <= 0.0 || numb_bins == 0 {
+        return Err(Error::InvalidOption("xi and bins of translational should be positive".to_string()));
+    }
 
-pub fn q(system: &Vec<Frame>, output: &str) -> Result<(), Box<dyn Error>> {
-    fs::File::create(&output).unwrap();
-    let mut q_answer: f64 = 0.0;
-    let mut nframe: i32 = 0;
-    for i in system.iter() {
-        nframe += 1;
-        q_answer += q_oneframe(i, &output)?;
+    let mut o = fs::File::create(&output)?;
+    o.write_all("# frame  t\n".as_bytes())?;
+    let mut stats = BlockAverage::new(1);
+    let mut g_total: Vec<f64> = vec![0.0; numb_bins];
+    let oneframe = |frame: &Frame| -> Result<Vec<f64>, Error> {
+        let points: Vec<[f64; 3]> = frame.atom.iter()
+            .filter(|x| x.type_name == element)
+            .map(|x| x.coordination)
+            .collect();
+        g_xi(points, &frame.cell, xi_c, numb_bins)
+    };
+    map_frames(system, oneframe, |i, g| {
+        let t: f64 = translational_order(&g);
+        o.write_all(format!("{}  {:.8}\n", i.frame_idx, t).as_bytes())?;
+        stats.push(&[t]);
+        for (s, x) in g_total.iter_mut().zip(g.iter()) {
+            *s += x;
+        }
+        Ok(())
+    })?;
+    if stats.is_empty() {
+        return Err(Error::InvalidOption("no frame for translational, please check frameopt".to_string()));
     }
-    println!("Averate q is {:.8}", q_answer / (nframe as f64));
+    let g_mean: Vec<f64> = g_total.iter().map(|x| x / stats.len() as f64).collect();
+    let t_mean_g: f64 = translational_order(&g_mean);
+    println!("Average t is {:.8} +- {:.8}, t of the average g(xi) is {:.8}", stats.mean()[0], stats.error().1[0], t_mean_g);
+    stats.write(&mut o, &["t".to_string()])?;
+    o.write_all(format!("#t_of_average_g {:.8}\n", t_mean_g).as_bytes())?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn perfect_tetrahedron() {
+        let vectors = [[1.0, 1.0, 1.0], [1.0, -1.0, -1.0], [-1.0, 1.0, -1.0], [-1.0, -1.0, 1.0]];
+        assert!((tetrahedral(&vectors) - 1.0).abs() < 1e-12);
+        assert!(local_translational(&vectors).abs() < 1e-12);
+    }
+
+    #[test]
+    fn translational_order_of_lattice() {
+        //  simple cubic lattice of 6x6x6, t does not depend on the lattice constant
+        let lattice = |a: f64| -> Vec<[f64; 3]> {
+            (0..216).map(|i| [(i % 6) as f64 * a, (i / 6 % 6) as f64 * a, (i / 36) as f64 * a]).collect()
+        };
+        let t1: f64 = translational_order(&g_xi(lattice(1.0), &Cell::from_lengths([6.0; 3]), 2.843, 200).unwrap());
+        let t3: f64 = translational_order(&g_xi(lattice(3.0), &Cell::from_lengths([18.0; 3]), 2.843, 200).unwrap());
+        assert!(t1 > 1.0 && (t1 - t3).abs() < 1e-9);
+        assert!(g_xi(lattice(1.0), &Cell::from_lengths([6.0; 3]), 3.5, 200).is_err());
+        assert_eq!(translational_order(&[1.0, 1.0]), 0.0);
+    }
+}
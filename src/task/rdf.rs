@@ -1,123 +1,230 @@
 //! This module contains the main function of computing radial distribution function.
 //!
-//! A commonly command for running this process is
-//! ```
-//! execfile ./a.xdatcar vasp/xdatcar 1 2000 5 rdf O O 8 320 ./rdf.1ps.dat
+//! Several pairs can be computed in one run, the taskopt is the element pairs
+//! followed by rcut and numb_bins, e.g.
+//! ```bash
+//! execfile --in ./a.xdatcar --infmt vasp/xdatcar --frameopt "1 2000 5" --task rdf --taskopt "O O O H H H 8 320" --out ./rdf.1ps.dat
 //! ```
+//! The running coordination number n(r) (the average number of B within r
+//! of an A) is written for each pair as well, and the first shell (first
+//! peak, first minimum after it, and n at the minimum) is given at the end.
+//!
+//! The histograms of all the frames are summed before normalising,
+//!
+//! g(r) = <count(r)> / (N_A * (N_B - d_AB) / <V> * 4/3 pi ((r+dr)^3 - r^3))
+//!
+//! where d_AB is 1 for A = B (the pair of an atom with itself is excluded)
+//! and <V> is the average volume, so NPT trajectories are fine. r is the
+//! center of each bin, and n(r) at the center is interpolated linearly.
+//!
+//! The error bar of g(r) is from the g(r) of each frame by block averaging
+//! (see task::stats).
 
-use crate::task::get_distance_pbc;
+use crate::task::neighbour::CellList;
+use crate::task::parallel::map_frames;
+use crate::task::stats::BlockAverage;
 use crate::{Atom, Frame};
-use std::error::Error;
+use crate::error::{parse_opt, Error};
 use std::f64::consts::PI;
 use std::fs;
 use std::io::Write;
 
-/// Compute the Rdf g(r)["a", "b"] in one frame,
-/// and the answer is stored in the form of g(r) = vec![].
+/// Count the A-B distances of each pair ["a", "b"] in one frame,
+/// the histogram (number of pairs in each bin of rcut/numb_bins) of each pair is returned.
 pub fn rdf_oneframe(
     frame: &Frame,
-    rdf_type: &[&str; 2],
+    rdf_type: &Vec<[String; 2]>,
     rcut: f64,
     numb_bins: i32,
-    vcell: &f64,
-) -> Result<Vec<f64>, Box<dyn Error>> {
-    
-    //  ------Collect the information of b------
-    let mut numb_a: i32 = 0;
-    for i in 0..frame.atom_type.len() {
-        if rdf_type[0] == &frame.atom_type[i] {
-            numb_a = frame.atom_numb[i]
+) -> Result<Vec<Vec<f64>>, Error> {
+    let dr: f64 = rcut / numb_bins as f64;
+    let mut counts: Vec<Vec<f64>> = vec![];
+    for pair in rdf_type.iter() {
+        //  ------Collect the coordination of a and b------
+        let coord_a: Vec<&Atom> = frame.atom.iter().filter(|x| x.type_name == pair[0]).collect();
+        let coord_b: Vec<&Atom> = frame.atom.iter().filter(|x| x.type_name == pair[1]).collect();
+
+        //  ------the main computation------
+        let mut count: Vec<f64> = vec![0.0; numb_bins as usize];
+        let list = CellList::new(coord_b.iter().map(|x| x.coordination).collect(), &frame.cell, rcut);
+        for i in &coord_a {
+            for (j, d) in list.within(i.coordination, rcut) {
+                //  not the atom itself for A = B
+                if coord_b[j].index == i.index {
+                    continue;
+                }
+                let layer = ((d / dr).floor() as usize).min(numb_bins as usize - 1);
+                count[layer] += 1.0;
+            }
         }
+        counts.push(count);
     }
-    let mut numb_b: i32 = 0;
+    Ok(counts)
+}
+
+/// Number of atoms of an element in one frame.
+fn numb_element(frame: &Frame, type_name: &str) -> i32 {
+    let mut numb: i32 = 0;
     for i in 0..frame.atom_type.len() {
-        if rdf_type[1] == &frame.atom_type[i] {
-            numb_b = frame.atom_numb[i]
+        if type_name == frame.atom_type[i] {
+            numb = frame.atom_numb[i]
         }
     }
-    //println!("numb_a = {} ; numb_b = {}", numb_a, numb_b);
+    numb
+}
 
-    //  ------Collect the coordination of a and b------
-    let mut coord_a: Vec<&Atom> = vec![];
-    let mut coord_b: Vec<&Atom> = vec![];
-    for i in 0..frame.atom.len() {
-        if &frame.atom[i].type_name == rdf_type[0] {
-            coord_a.push(&frame.atom[i])
-        }
-        if &frame.atom[i].type_name == rdf_type[1] {
-            coord_b.push(&frame.atom[i])
+/// The first peak of g, the first minimum after it, as the index of the bins.
+pub fn first_shell(g: &Vec<f64>) -> Option<(usize, usize)> {
+    let mut peak: Option<usize> = None;
+    for i in 0..g.len() {
+        if g[i].is_finite() && peak.map_or(true, |p| g[i] > g[p]) {
+            peak = Some(i);
         }
     }
-
-    //  ------the main computation------
-    let mut distance: Vec<f64> = vec![];
-    let dr: f64 = rcut / numb_bins as f64;
-    let mut count: Vec<f64> = vec![0.0; numb_bins as usize];
-
-    for i in &coord_a {
-        for j in &coord_b {
-            let d: f64 = get_distance_pbc(i.coordination, j.coordination, &frame.cell);
-            if d < rcut {
-                let layer = (d / dr).floor() as usize;
-                distance.push(d);
-                count[layer] += 1.0;
-            }
-        }
-    }
-    //println!("{:?}", count);
-    let rho_b: f64 = (numb_b) as f64 / vcell;
-    for i in 0..numb_bins as usize {
-        let n: f64 = i as f64;
-        //count[i] = count[i] / ((numb_b as f64) * 4.0 * PI * (n * dr).powi(2) * dr * rho_b);
-        // Using numb_b should be wrong.
-        count[i] = count[i] / ((numb_a as f64) * 4.0 * PI * (n * dr).powi(2) * dr * rho_b);
-    }
-    //println!("{:?}", count);
-    Ok(count)
+    let peak: usize = peak?;
+    (peak + 1..g.len().saturating_sub(1))
+        .find(|&i| g[i] <= g[i - 1] && g[i] < g[i + 1])
+        .map(|minimum| (peak, minimum))
 }
 
-/// Create the output file and put all covalence angle of all the frames
-/// into the output file.
-/// rdfopt = vec!["elementA", "elementB", "rcut", "numb_bins"];
-pub fn rdf(
-    system: &Vec<Frame>,
+/// Compute the rdf of all the pairs, and put "r  g(r)...  n(r)..." into the output file.
+/// rdfopt = vec!["elementA", "elementB", ("elementC", "elementD", ...), "rcut", "numb_bins"];
+///
+/// The frames are consumed one batch at a time and computed in parallel.
+pub fn rdf<I>(
+    system: I,
     rdfopt: &Vec<&str>,
     output: &str,
-) -> Result<(), Box<dyn Error>> {
+) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
 
     //  ------load the task option------
-    let rdf_type: [&str; 2] = [&rdfopt[0], &rdfopt[1]];
-    let rcut: f64 = rdfopt[2].parse::<f64>().unwrap();
-    let numb_bins: i32 = rdfopt[3].parse::<i32>().unwrap();
+    let nopt: usize = rdfopt.len();
+    if nopt < 4 || nopt % 2 != 0 {
+        return Err(Error::InvalidOption(format!(
+            "rdf needs pairs of elements, rcut and numb_bins, {} option(s) are given: {:?}", nopt, rdfopt)));
+    }
+    let rdf_type: Vec<[String; 2]> = rdfopt[..nopt - 2]
+        .chunks(2)
+        .map(|x| [x[0].to_string(), x[1].to_string()])
+        .collect();
+    let npair: usize = rdf_type.len();
+    let rcut: f64 = parse_opt(rdfopt, nopt - 2, "rcut")?;
+    let numb_bins: i32 = parse_opt(rdfopt, nopt - 1, "numb_bins")?;
+    if rcut <= 0.0 || numb_bins <= 0 {
+        return Err(Error::InvalidOption("rcut and numb_bins of rdf should be positive".to_string()));
+    }
+    let nbins: usize = numb_bins as usize;
+    let dr: f64 = rcut / numb_bins as f64;
+    let shell: Vec<f64> = (0..nbins)
+        .map(|j| 4.0 / 3.0 * PI * ((dr * (j + 1) as f64).powi(3) - (dr * j as f64).powi(3)))
+        .collect();
 
-    //  ------Compute rdf and loop for frames------
-    let mut gr: Vec<f64> = vec![0.0; numb_bins as usize];
+    //  ------Accumulate the histograms and loop for frames------
+    let mut count_total: Vec<Vec<f64>> = vec![vec![0.0; nbins]; npair];
+    let mut numb_a_total: Vec<f64> = vec![0.0; npair];
+    let mut numb_pair_total: Vec<f64> = vec![0.0; npair];    // N_A * (N_B - d_AB)
+    let mut volume_total: f64 = 0.0;
     let mut nframe: i32 = 0;
+    let mut stats = BlockAverage::new(npair * nbins);    // g(r) of each frame, for the error bars
 
-    for i in system.iter() {
-        //println!("{}",nframe);
-        nframe += 1;
-        let vcell: f64 = i.cell[0] * i.cell[1] * i.cell[2];
-        let gr_oneframe = rdf_oneframe(i, &rdf_type, rcut, numb_bins, &vcell)?;
-        for j in 0..numb_bins as usize {
-            gr[j] = gr[j] + gr_oneframe[j];
+    map_frames(
+        system,
+        |i| rdf_oneframe(i, &rdf_type, rcut, numb_bins),
+        |i, counts| {
+            nframe += 1;
+            volume_total += i.cell.volume();
+            let mut g_frame: Vec<f64> = vec![0.0; npair * nbins];
+            for (p, count) in counts.iter().enumerate() {
+                let numb_a: f64 = numb_element(i, &rdf_type[p][0]) as f64;
+                let mut numb_b: f64 = numb_element(i, &rdf_type[p][1]) as f64;
+                if rdf_type[p][0] == rdf_type[p][1] {
+                    numb_b -= 1.0;
+                }
+                if numb_a * numb_b == 0.0 {
+                    return Err(Error::InvalidOption(format!(
+                        "no {}-{} pair in frame {}, check the elements (or --typemap for lammps)",
+                        rdf_type[p][0], rdf_type[p][1], i.frame_idx)));
+                }
+                for j in 0..nbins {
+                    count_total[p][j] += count[j];
+                    g_frame[p * nbins + j] = count[j] / (numb_a * numb_b / i.cell.volume() * shell[j]);
+                }
+                numb_a_total[p] += numb_a;
+                numb_pair_total[p] += numb_a * numb_b;
+            }
+            stats.push(&g_frame);
+            Ok(())
+        },
+    )?;
+    println!("nframe is {}", nframe);
+    if nframe == 0 {
+        return Err(Error::InvalidOption("no frame is selected, please check frameopt".to_string()));
+    }
+
+    //  ------normalise with the average density and the exact shell volume------
+    let volume: f64 = volume_total / nframe as f64;
+    let mut gr: Vec<Vec<f64>> = vec![vec![0.0; nbins]; npair];
+    for p in 0..npair {
+        for j in 0..nbins {
+            gr[p][j] = count_total[p][j] / (numb_pair_total[p] / volume * shell[j]);
         }
     }
-    println!("nframe is {}", nframe);
-    for j in 0..numb_bins as usize {
-        gr[j] = gr[j] / nframe as f64;
+    let (block_size, error) = stats.error();
+
+    //  ------running coordination number at the bin centers------
+    let mut nr: Vec<Vec<f64>> = vec![vec![0.0; nbins]; npair];
+    for p in 0..npair {
+        let mut below: f64 = 0.0;     // number of B below the bin
+        for j in 0..nbins {
+            let n: f64 = count_total[p][j] / numb_a_total[p];
+            nr[p][j] = below + 0.5 * n;
+            below += n;
+        }
     }
 
     //  ------write into output file------
-    //  "r[i]   g[i]   \n"
-    let mut r: Vec<f64> = vec![];
-    for i in 0..numb_bins {
-        r.push((rcut / numb_bins as f64) * i as f64);
+    //  "r[i]   g[i]...   n[i]...\n"
+    let mut o = fs::File::create(output)?;
+    let names: Vec<String> = rdf_type.iter().map(|x| format!("{}-{}", x[0], x[1])).collect();
+    let mut header: String = "# r".to_string();
+    for name in names.iter() {
+        header += &format!("  g_{}", name);
+    }
+    for name in names.iter() {
+        header += &format!("  n_{}", name);
     }
-    let mut o = fs::File::create(output).unwrap();
-    for i in 0..numb_bins as usize {
-        o.write((format!("{:.4}  {:.8}", r[i], gr[i]) + "\n").as_bytes())
-            .expect("write rdf to file failed");
+    for name in names.iter() {
+        header += &format!("  err_g_{}", name);
+    }
+    o.write_all((header + "\n").as_bytes())?;
+    for j in 0..nbins {
+        let mut line: String = format!("{:.4}", dr * (j as f64 + 0.5));
+        for p in 0..npair {
+            line += &format!("  {:.8}", gr[p][j]);
+        }
+        for p in 0..npair {
+            line += &format!("  {:.8}", nr[p][j]);
+        }
+        for p in 0..npair {
+            line += &format!("  {:.8}", error[p * nbins + j]);
+        }
+        o.write_all((line + "\n").as_bytes())?;
+    }
+
+    //  ------first shell of each pair------
+    for p in 0..npair {
+        let line: String = match first_shell(&gr[p]) {
+            Some((peak, minimum)) => format!(
+                "#first_shell {}  r_peak {:.4}  g_peak {:.8}  r_min {:.4}  n(r_min) {:.8}",
+                names[p], dr * (peak as f64 + 0.5), gr[p][peak], dr * (minimum as f64 + 0.5), nr[p][minimum]),
+            None => format!("#first_shell {}  not found", names[p]),
+        };
+        println!("{}", &line[1..]);
+        o.write_all((line + "\n").as_bytes())?;
     }
+    o.write_all(format!("#error by block averaging, block_size {} of {} frames\n", block_size, nframe).as_bytes())?;
     Ok(())
 }
@@ -0,0 +1,393 @@
+//! Statistical errors of the averages over a trajectory.
+//!
+//! The frames of an md trajectory are correlated, so the standard error of
+//! the mean computed from the per-frame values is too small. The values are
+//! cut into blocks of 1, 2, 4, ... frames (Flyvbjerg and Petersen, J. Chem.
+//! Phys. 91, 461 (1989)), the standard error from the block averages grows
+//! with the block size until the blocks are longer than the correlation
+//! time, and then stays on a plateau. The error reported is the largest one
+//! over the block sizes with at least MIN_BLOCKS blocks, and the whole table
+//! is written too, so that the plateau can be checked. A moving block
+//! bootstrap with the same block size is given as a second estimate. The
+//! blocks are summed on the fly, so that the frames are not kept in memory.
+//!
+//! Each task pushes the values of one frame (a scalar, or a curve such as
+//! g(r)) in the order of the frames, e.g.
+//! ```rust,ignore
+//! let mut stats = BlockAverage::new(1);
+//! map_frames(system, oneframe, |_, x| { stats.push(&[x]); Ok(()) })?;
+//! stats.write(&mut o, &["q".to_string()])?;
+//! ```
+//!
+//! Histogram is the normalised distribution of a value, e.g. the O-H length.
+
+use crate::error::Error;
+use std::io::Write;
+
+/// Smallest number of blocks used for the error estimate.
+pub const MIN_BLOCKS: usize = 8;
+
+/// Number of resamples of the bootstrap.
+const NUMB_SAMPLE: usize = 200;
+
+/// Largest number of block averages kept for the bootstrap, see BlockAverage.
+const MAX_STORED: usize = 1024;
+
+/// The blocks of 2^l frames of one level l.
+#[derive(Debug, Clone)]
+struct Level {
+    pending : Option<Vec<f64>>,   // the first half of the next block
+    numb    : usize,              // number of complete blocks
+    mean    : Vec<f64>,           // running mean and sum of squared deviations of the
+    m2      : Vec<f64>,           // block averages (Welford)
+}
+
+impl Level {
+    fn new(dim: usize) -> Level {
+        Level { pending: None, numb: 0, mean: vec![0.0; dim], m2: vec![0.0; dim] }
+    }
+
+    fn add(&mut self, x: &[f64]) {
+        self.numb += 1;
+        for ((v, mean), m2) in x.iter().zip(self.mean.iter_mut()).zip(self.m2.iter_mut()) {
+            let delta: f64 = v - *mean;
+            *mean += delta / self.numb as f64;
+            *m2 += delta * (v - *mean);
+        }
+    }
+
+    /// Standard error of the mean of the blocks, None with less than 2 blocks.
+    fn error(&self) -> Option<Vec<f64>> {
+        if self.numb < 2 {
+            return None;
+        }
+        let n: f64 = self.numb as f64;
+        Some(self.m2.iter().map(|m| (m / ((n - 1.0) * n)).sqrt()).collect())
+    }
+}
+
+/// Averages and errors of dim observables over the frames.
+///
+/// The frames are not kept, the memory does not grow with the trajectory:
+/// each level l has the running mean and variance of its blocks of 2^l
+/// frames, a block is the average of two blocks of the level below. For the
+/// bootstrap, at most MAX_STORED block averages of the finest level that fits
+/// are kept, two neighbours are merged when they are too many.
+pub struct BlockAverage {
+    dim          : usize,
+    numb         : usize,           // number of frames
+    sum          : Vec<f64>,
+    levels       : Vec<Level>,
+    stored       : Vec<Vec<f64>>,   // averages of the blocks of 2^stored_level frames
+    stored_level : usize,
+}
+
+/// Standard error of the mean of independent samples.
+fn standard_error(samples: &[Vec<f64>], dim: usize) -> Vec<f64> {
+    let n: usize = samples.len();
+    let mut mean: Vec<f64> = vec![0.0; dim];
+    for x in samples.iter() {
+        for k in 0..dim {
+            mean[k] += x[k] / n as f64;
+        }
+    }
+    let mut var: Vec<f64> = vec![0.0; dim];
+    for x in samples.iter() {
+        for k in 0..dim {
+            var[k] += (x[k] - mean[k]).powi(2);
+        }
+    }
+    var.iter().map(|v| (v / ((n - 1) * n) as f64).sqrt()).collect()
+}
+
+/// Average of two rows.
+fn pair_average(a: &[f64], b: &[f64]) -> Vec<f64> {
+    a.iter().zip(b.iter()).map(|(x, y)| 0.5 * (x + y)).collect()
+}
+
+impl BlockAverage {
+    /// For dim values in each frame.
+    pub fn new(dim: usize) -> BlockAverage {
+        BlockAverage { dim, numb: 0, sum: vec![0.0; dim], levels: vec![], stored: vec![], stored_level: 0 }
+    }
+
+    /// Add the values of the next frame.
+    pub fn push(&mut self, x: &[f64]) {
+        assert_eq!(x.len(), self.dim, "wrong number of values for BlockAverage");
+        self.numb += 1;
+        for (s, v) in self.sum.iter_mut().zip(x.iter()) {
+            *s += v;
+        }
+        //  a complete block of level l is carried to level l+1
+        let mut value: Vec<f64> = x.to_vec();
+        let mut l: usize = 0;
+        loop {
+            if l == self.levels.len() {
+                self.levels.push(Level::new(self.dim));
+            }
+            self.levels[l].add(&value);
+            if l == self.stored_level {
+                self.stored.push(value.clone());
+                if self.stored.len() == MAX_STORED {
+                    //  the last pair is completed at the next level in this loop, and stored there
+                    self.stored = self.stored[..MAX_STORED - 2].chunks(2).map(|x| pair_average(&x[0], &x[1])).collect();
+                    self.stored_level += 1;
+                }
+            }
+            match self.levels[l].pending.take() {
+                None => {
+                    self.levels[l].pending = Some(value);
+                    break;
+                }
+                Some(first) => {
+                    value = pair_average(&first, &value);
+                    l += 1;
+                }
+            }
+        }
+    }
+
+    /// Number of frames.
+    pub fn len(&self) -> usize {
+        self.numb
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.numb == 0
+    }
+
+    /// Average over all the frames.
+    pub fn mean(&self) -> Vec<f64> {
+        self.sum.iter().map(|s| s / self.numb as f64).collect()
+    }
+
+    /// Standard error of the mean from the averages of blocks of block_size frames,
+    /// the frames left at the end are not used. None if there are less than 2 blocks,
+    /// or if block_size is not a power of 2.
+    pub fn block_error(&self, block_size: usize) -> Option<Vec<f64>> {
+        if !block_size.is_power_of_two() {
+            return None;
+        }
+        self.levels.get(block_size.trailing_zeros() as usize)?.error()
+    }
+
+    /// (block_size, numb_block, error) for block_size = 1, 2, 4, ... as long as there are 2 blocks.
+    pub fn convergence(&self) -> Vec<(usize, usize, Vec<f64>)> {
+        self.levels.iter().enumerate()
+            .filter_map(|(l, level)| level.error().map(|error| (1 << l, level.numb, error)))
+            .collect()
+    }
+
+    /// The block size and the error estimate, the largest error of each value over the
+    /// block sizes with at least MIN_BLOCKS blocks (block size 1 for a short trajectory).
+    /// The error is NaN with less than 2 frames.
+    pub fn error(&self) -> (usize, Vec<f64>) {
+        let table = self.convergence();
+        if table.is_empty() {
+            return (1, vec![f64::NAN; self.dim]);
+        }
+        let mut block_size: usize = 1;
+        let mut error: Vec<f64> = table[0].2.clone();
+        let mut largest: f64 = error.iter().sum();
+        for (size, numb_block, err) in table.into_iter().skip(1) {
+            if numb_block < MIN_BLOCKS {
+                break;
+            }
+            //  the block size is the one of the largest total error
+            let total: f64 = err.iter().sum();
+            if total > largest {
+                largest = total;
+                block_size = size;
+            }
+            for k in 0..self.dim {
+                error[k] = error[k].max(err[k]);
+            }
+        }
+        (block_size, error)
+    }
+
+    /// Standard error of the mean by the moving block bootstrap, each resample is made
+    /// of blocks of block_size frames starting at random frames. For a long trajectory
+    /// the stored block averages are resampled, so the blocks are at least as long
+    /// as them. The random numbers are from a fixed seed, so the answer is reproducible.
+    pub fn bootstrap(&self, block_size: usize, numb_sample: usize) -> Vec<f64> {
+        let size: usize = (block_size >> self.stored_level).max(1);    // in stored blocks
+        let n: usize = self.stored.len();
+        if block_size == 0 || n < 2 * size || numb_sample < 2 {
+            return vec![f64::NAN; self.dim];
+        }
+        let numb_block: usize = n / size;
+        let numb_start: u64 = (n - size + 1) as u64;
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut samples: Vec<Vec<f64>> = vec![];
+        for _ in 0..numb_sample {
+            let mut sum: Vec<f64> = vec![0.0; self.dim];
+            for _ in 0..numb_block {
+                //  xorshift64
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                let start: usize = (seed % numb_start) as usize;
+                for x in self.stored[start..start + size].iter() {
+                    for k in 0..self.dim {
+                        sum[k] += x[k];
+                    }
+                }
+            }
+            samples.push(sum.iter().map(|s| s / (numb_block * size) as f64).collect());
+        }
+        //  the spread of the resampled means is the error itself, not divided by sqrt(numb_sample)
+        standard_error(&samples, self.dim).iter().map(|e| e * (numb_sample as f64).sqrt()).collect()
+    }
+
+    /// Write the mean, the errors and the block size convergence table as comments, i.e.
+    /// "#mean", "#error" (block averaging), "#bootstrap" and "#block_size  numb_block  error..."
+    pub fn write<W: Write>(&self, o: &mut W, names: &[String]) -> Result<(), Error> {
+        let (block_size, error) = self.error();
+        let bootstrap: Vec<f64> = self.bootstrap(block_size, NUMB_SAMPLE);
+        let join = |x: &[f64]| -> String { x.iter().map(|v| format!("  {:.8}", v)).collect() };
+        let names: String = names.iter().map(|x| format!("  {}", x)).collect();
+        o.write_all(format!("#frames {}  block_size {}\n", self.len(), block_size).as_bytes())?;
+        o.write_all(format!("#name{}\n", names).as_bytes())?;
+        o.write_all(format!("#mean{}\n", join(&self.mean())).as_bytes())?;
+        o.write_all(format!("#error{}\n", join(&error)).as_bytes())?;
+        o.write_all(format!("#bootstrap{}\n", join(&bootstrap)).as_bytes())?;
+        o.write_all(format!("#block_size  numb_block{}\n", names).as_bytes())?;
+        for (size, numb_block, err) in self.convergence() {
+            o.write_all(format!("#{}  {}{}\n", size, numb_block, join(&err)).as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Distribution of a value in nbins bins from min to max, with the mean and
+/// the standard deviation of all the values (also the ones out of the range).
+pub struct Histogram {
+    pub min    : f64,
+    pub max    : f64,
+    pub count  : Vec<f64>,
+    numb       : usize,   // number of values, in the range or not
+    sum        : f64,
+    sum_square : f64,
+}
+
+impl Histogram {
+    pub fn new(min: f64, max: f64, nbins: usize) -> Result<Histogram, Error> {
+        if !(max > min) || nbins == 0 {
+            return Err(Error::InvalidOption(format!(
+                "the range of a histogram should be min < max with bins > 0, not {} {} {}", min, max, nbins)));
+        }
+        Ok(Histogram { min, max, count: vec![0.0; nbins], numb: 0, sum: 0.0, sum_square: 0.0 })
+    }
+
+    /// Read "min,max,bins", name is used in the message.
+    pub fn parse(value: &str, name: &str) -> Result<Histogram, Error> {
+        let wrong = || Error::InvalidOption(format!("the histogram of {} should be min,max,bins, not \"{}\"", name, value));
+        let words: Vec<&str> = value.split(',').collect();
+        if words.len() != 3 {
+            return Err(wrong());
+        }
+        let min: f64 = words[0].parse().map_err(|_| wrong())?;
+        let max: f64 = words[1].parse().map_err(|_| wrong())?;
+        let nbins: usize = words[2].parse().map_err(|_| wrong())?;
+        Histogram::new(min, max, nbins)
+    }
+
+    pub fn width(&self) -> f64 {
+        (self.max - self.min) / self.count.len() as f64
+    }
+
+    /// Center of the i-th bin.
+    pub fn center(&self, i: usize) -> f64 {
+        self.min + (i as f64 + 0.5) * self.width()
+    }
+
+    /// The bin of x, None if out of the range.
+    pub fn bin(&self, x: f64) -> Option<usize> {
+        if x >= self.min && x < self.max {
+            Some((((x - self.min) / self.width()) as usize).min(self.count.len() - 1))
+        } else {
+            None
+        }
+    }
+
+    pub fn add(&mut self, x: f64) {
+        if let Some(i) = self.bin(x) {
+            self.count[i] += 1.0;
+        }
+        self.numb += 1;
+        self.sum += x;
+        self.sum_square += x * x;
+    }
+
+    /// Probability density of each bin, normalised with all the values,
+    /// so it integrates to less than 1 if some values are out of the range.
+    pub fn density(&self) -> Vec<f64> {
+        let norm: f64 = self.numb.max(1) as f64 * self.width();
+        self.count.iter().map(|c| c / norm).collect()
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.numb as f64
+    }
+
+    pub fn std(&self) -> f64 {
+        let mean: f64 = self.mean();
+        (self.sum_square / self.numb as f64 - mean * mean).max(0.0).sqrt()
+    }
+
+    /// Write "# name  P(name)", the mean and the std, then "center  density" of each bin.
+    pub fn write<W: Write>(&self, o: &mut W, name: &str) -> Result<(), Error> {
+        let outside: f64 = self.numb as f64 - self.count.iter().sum::<f64>();
+        o.write_all(format!("# mean {:.8}  std {:.8}  values {}  out_of_range {}\n",
+            self.mean(), self.std(), self.numb, outside).as_bytes())?;
+        o.write_all(format!("# {}  P({})\n", name, name).as_bytes())?;
+        for (i, p) in self.density().iter().enumerate() {
+            o.write_all(format!("{:.6}  {:.8}\n", self.center(i), p).as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_of_correlated_values() {
+        //  pairs of equal values, the error of blocks of 2 is the one of independent values
+        let mut stats = BlockAverage::new(1);
+        for x in [1.0, 3.0, 2.0, 5.0, 4.0, 0.0, 2.0, 7.0] {
+            stats.push(&[x]);
+            stats.push(&[x]);
+        }
+        assert_eq!(stats.mean(), vec![3.0]);
+        let independent: f64 = (36.0_f64 / 7.0 / 8.0).sqrt();
+        assert!((stats.block_error(2).unwrap()[0] - independent).abs() < 1e-12);
+        assert!(stats.block_error(1).unwrap()[0] < independent);
+        assert_eq!(stats.convergence().len(), 4);
+        assert!(stats.block_error(16).is_none());
+        assert!(stats.block_error(3).is_none());
+    }
+
+    #[test]
+    fn same_as_stored_frames() {
+        //  a correlated series, long enough to merge the stored blocks several times
+        let x: Vec<f64> = (0..5000).map(|i| (i as f64 * 0.01).sin() + ((i * 7919) % 101) as f64 / 101.0).collect();
+        let mut stats = BlockAverage::new(1);
+        for v in x.iter() {
+            stats.push(&[*v]);
+        }
+        assert!(stats.stored.len() < MAX_STORED);
+        assert_eq!(stats.stored.len(), x.len() >> stats.stored_level);
+        for size in [1, 4, 64, 1024] {
+            let blocks: Vec<Vec<f64>> = x[..x.len() / size * size].chunks(size)
+                .map(|b| vec![b.iter().sum::<f64>() / size as f64])
+                .collect();
+            let direct: f64 = standard_error(&blocks, 1)[0];
+            assert!((stats.block_error(size).unwrap()[0] - direct).abs() < 1e-10);
+        }
+        assert!((stats.mean()[0] - x.iter().sum::<f64>() / 5000.0).abs() < 1e-12);
+        assert!(stats.bootstrap(64, 200)[0].is_finite());
+    }
+}
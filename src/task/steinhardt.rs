@@ -0,0 +1,288 @@
+//! Bond-orientational order parameters and the ice-like molecules.
+//!
+//! With the neighbours N(i) of each center atom (see task::q, the numb = 4
+//! nearest O within rcut = 3.5 A by default, fewer if there are not enough),
+//! - q_lm(i) = 1/|N(i)| sum_{j in N(i)} Y_lm(r_ij)
+//! - Steinhardt, q_l(i) = sqrt(4 pi / (2l+1) sum_m |q_lm(i)|^2)
+//! - Lechner and Dellago (J. Chem. Phys. 129, 114707 (2008)), q̄_l(i) is
+//!   the same with q̄_lm(i), the average of q_lm over i and N(i)
+//!
+//! The molecules are classified by CHILL+ (Nguyen and Molinero, J. Phys.
+//! Chem. B 119, 9369 (2015)) from the correlation of q_3m of the bonded
+//! pairs, c(i,j) = Re sum_m q_3m(i) q_3m(j)* / (|q_3(i)| |q_3(j)|), a bond
+//! is staggered if c <= -0.8 and eclipsed if -0.35 <= c <= 0.25:
+//! - hydrate: 4 eclipsed bonds, interfacial hydrate: 3 eclipsed bonds
+//! - cubic ice (Ic): 4 staggered bonds
+//! - hexagonal ice (Ih): 3 staggered and 1 eclipsed bonds
+//! - interfacial ice: 3 staggered and no eclipsed bonds, or 2 staggered bonds
+//! - liquid: the others
+//!
+//! steinhardtopt is optional, center=, neighbour=, numb= and rcut= as task q
+//! (center and neighbour should be the same element), and l=4,6 the values of l.
+//! ```bash
+//! execfile --in ./a.lammpstrj --infmt lammps/traj --typemap "O H" --task steinhardt --taskopt "l=4,6" --out ./ice.dat
+//! ```
+
+use crate::error::Error;
+use crate::task::neighbour::CellList;
+use crate::task::parallel::map_frames;
+use crate::task::q::Shell;
+use crate::task::stats::BlockAverage;
+use crate::task::get_vector_pbc;
+use crate::{Atom, Frame};
+use rustfft::num_complex::Complex;
+use std::f64::consts::PI;
+use std::fs;
+use std::io::Write;
+
+/// The CHILL+ classes, in the order of the output.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Ice {
+    Hexagonal,
+    Cubic,
+    Interfacial,
+    Hydrate,
+    InterfacialHydrate,
+    Liquid,
+}
+
+const ICE_NAME: [&str; 6] = ["Ih", "Ic", "interfacial_ice", "hydrate", "interfacial_hydrate", "liquid"];
+
+impl Ice {
+    /// Classify a molecule from its numbers of staggered and eclipsed bonds.
+    pub fn classify(numb_staggered: usize, numb_eclipsed: usize) -> Ice {
+        match (numb_staggered, numb_eclipsed) {
+            (_, 4) => Ice::Hydrate,
+            (_, 3) => Ice::InterfacialHydrate,
+            (4, _) => Ice::Cubic,
+            (3, 1) => Ice::Hexagonal,
+            (3, 0) | (2, _) => Ice::Interfacial,
+            _ => Ice::Liquid,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        ICE_NAME[*self as usize]
+    }
+}
+
+/// Y_lm of the direction of v for m = 0..l (Y_l,-m = (-1)^m Y_lm*).
+pub fn spherical_harmonics(l: usize, v: [f64; 3]) -> Vec<Complex<f64>> {
+    let r: f64 = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    let x: f64 = v[2] / r;                          // cos(theta)
+    let phi: f64 = v[1].atan2(v[0]);
+    let s: f64 = (1.0 - x * x).max(0.0).sqrt();     // sin(theta)
+    let mut ylm: Vec<Complex<f64>> = vec![];
+    for m in 0..=l {
+        //  associated Legendre polynomial P_l^m(x) with the Condon-Shortley phase
+        let mut pmm: f64 = 1.0;
+        for k in 0..m {
+            pmm *= -((2 * k + 1) as f64) * s;
+        }
+        let plm: f64 = if l == m {
+            pmm
+        } else {
+            let mut p0: f64 = pmm;
+            let mut p1: f64 = x * (2 * m + 1) as f64 * pmm;
+            for n in (m + 2)..=l {
+                let p2: f64 = ((2 * n - 1) as f64 * x * p1 - (n + m - 1) as f64 * p0) / (n - m) as f64;
+                p0 = p1;
+                p1 = p2;
+            }
+            p1
+        };
+        //  (l-m)!/(l+m)!
+        let ratio: f64 = ((l - m + 1)..=(l + m)).fold(1.0, |a, k| a / k as f64);
+        let norm: f64 = ((2 * l + 1) as f64 / (4.0 * PI) * ratio).sqrt();
+        ylm.push(Complex::from_polar(norm * plm, m as f64 * phi));
+    }
+    ylm
+}
+
+/// sum_m a_lm b_lm* for m = -l..l from m = 0..l, which is real.
+fn dot(a: &[Complex<f64>], b: &[Complex<f64>]) -> f64 {
+    let mut sum: f64 = (a[0] * b[0].conj()).re;
+    for m in 1..a.len() {
+        sum += 2.0 * (a[m] * b[m].conj()).re;
+    }
+    sum
+}
+
+/// q_l from q_lm, m = 0..l.
+fn order(qlm: &[Complex<f64>]) -> f64 {
+    let l: usize = qlm.len() - 1;
+    (4.0 * PI / (2 * l + 1) as f64 * dot(qlm, qlm)).sqrt()
+}
+
+/// q_lm (m = 0..l) of each center atom.
+fn local(l: usize, bonds: &[Vec<(usize, [f64; 3])>]) -> Vec<Vec<Complex<f64>>> {
+    bonds.iter().map(|neighbour| {
+        let mut qlm: Vec<Complex<f64>> = vec![Complex::new(0.0, 0.0); l + 1];
+        for (_, v) in neighbour.iter() {
+            for (q, y) in qlm.iter_mut().zip(spherical_harmonics(l, *v)) {
+                *q += y / neighbour.len() as f64;
+            }
+        }
+        qlm
+    }).collect()
+}
+
+/// The order parameters of one center atom.
+#[derive(Debug)]
+pub struct Bond {
+    pub index       : i32,
+    pub numb        : usize,      // number of neighbours
+    pub ql          : Vec<f64>,   // q_l of each l
+    pub ql_average  : Vec<f64>,   // q̄_l of each l
+    pub ice         : Ice,
+}
+
+/// Compute q_l, q̄_l and the CHILL+ class of each center atom in one frame,
+/// in the order of the atoms. An atom without neighbours has NaN q_l.
+pub fn steinhardt_oneframe(frame: &Frame, shell: &Shell, ls: &[usize]) -> Result<Vec<Bond>, Error> {
+    let coord: Vec<&Atom> = frame.atom.iter().filter(|x| x.type_name == shell.center).collect();
+    let list = CellList::new(coord.iter().map(|x| x.coordination).collect(), &frame.cell, shell.rcut);
+    let bonds: Vec<Vec<(usize, [f64; 3])>> = coord.iter().map(|atom| {
+        list.nearest(atom.coordination, shell.numb, 0.1).into_iter()
+            .map(|(j, _)| (j, get_vector_pbc(atom.coordination, coord[j].coordination, &frame.cell)))
+            .collect()
+    }).collect();
+
+    let mut ql: Vec<Vec<f64>> = vec![vec![]; coord.len()];
+    let mut ql_average: Vec<Vec<f64>> = vec![vec![]; coord.len()];
+    for &l in ls.iter() {
+        let qlm = local(l, &bonds);
+        for i in 0..coord.len() {
+            let mut average: Vec<Complex<f64>> = qlm[i].clone();
+            for (j, _) in bonds[i].iter() {
+                for m in 0..=l {
+                    average[m] += qlm[*j][m];
+                }
+            }
+            let average: Vec<Complex<f64>> = average.iter().map(|x| x / (bonds[i].len() + 1) as f64).collect();
+            ql[i].push(if bonds[i].is_empty() { f64::NAN } else { order(&qlm[i]) });
+            ql_average[i].push(if bonds[i].is_empty() { f64::NAN } else { order(&average) });
+        }
+    }
+
+    //  ------CHILL+------
+    let q3 = local(3, &bonds);
+    let norm: Vec<f64> = q3.iter().map(|x| dot(x, x).sqrt()).collect();
+    Ok((0..coord.len()).map(|i| {
+        let mut numb_staggered: usize = 0;
+        let mut numb_eclipsed: usize = 0;
+        for (j, _) in bonds[i].iter() {
+            let c: f64 = dot(&q3[i], &q3[*j]) / (norm[i] * norm[*j]);
+            if c <= -0.8 {
+                numb_staggered += 1;
+            } else if (-0.35..=0.25).contains(&c) {
+                numb_eclipsed += 1;
+            }
+        }
+        Bond {
+            index: coord[i].index,
+            numb: bonds[i].len(),
+            ql: ql[i].clone(),
+            ql_average: ql_average[i].clone(),
+            ice: Ice::classify(numb_staggered, numb_eclipsed),
+        }
+    }).collect())
+}
+
+/// Compute the order parameters of all the frames.
+///
+/// Two files are written, output: "frame  index  numb  q_l...  q̄_l...  class" of each
+/// center atom, output.frame: the averages of q_l and q̄_l and the number of the
+/// molecules of each class in each frame, with the errors (see task::stats).
+/// The frames are consumed one batch at a time and computed in parallel.
+pub fn steinhardt<I>(system: I, steinhardtopt: &Vec<&str>, output: &str) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
+    let (mut shell, others) = Shell::from_opt(steinhardtopt)?;
+    if !steinhardtopt.iter().any(|x| x.starts_with("rcut=")) {
+        shell.rcut = 3.5;
+    }
+    if shell.center != shell.neighbour {
+        return Err(Error::InvalidOption(format!(
+            "center and neighbour of steinhardt should be the same element, not {} {}", shell.center, shell.neighbour)));
+    }
+    let mut ls: Vec<usize> = vec![4, 6];
+    for (key, value) in others {
+        if key != "l" {
+            return Err(Error::InvalidOption(format!(
+                "unknown steinhardt option {}, should be center, neighbour, numb, rcut or l", key)));
+        }
+        ls = value.split(',').map(|x| x.parse::<usize>()).collect::<Result<Vec<usize>, _>>()
+            .map_err(|_| Error::InvalidOption(format!("l of steinhardt should be like 4,6, not \"{}\"", value)))?;
+    }
+    println!("neighbours: {:?}, l: {:?}", shell, ls);
+
+    let mut names: Vec<String> = ls.iter().map(|l| format!("q{}", l)).collect();
+    names.extend(ls.iter().map(|l| format!("qbar{}", l)));
+    let mut o = fs::File::create(&output)?;
+    let mut o_frame = fs::File::create(format!("{}.frame", output))?;
+    o.write_all(format!("# frame  index  numb  {}  class\n", names.join("  ")).as_bytes())?;
+    names.extend(ICE_NAME.iter().map(|x| x.to_string()));
+    o_frame.write_all(format!("# frame  {}\n", names.join("  ")).as_bytes())?;
+
+    let nl: usize = ls.len();
+    let mut stats = BlockAverage::new(2 * nl + ICE_NAME.len());
+    map_frames(system, |i| steinhardt_oneframe(i, &shell, &ls), |i, bonds| {
+        let mut average: Vec<f64> = vec![0.0; 2 * nl + ICE_NAME.len()];
+        let mut numb: usize = 0;
+        for b in bonds.iter() {
+            let values: String = b.ql.iter().chain(b.ql_average.iter()).map(|x| format!("  {:.8}", x)).collect();
+            o.write_all(format!("{}  {}  {}{}  {}\n", i.frame_idx, b.index, b.numb, values, b.ice.name()).as_bytes())?;
+            average[2 * nl + b.ice as usize] += 1.0;
+            if b.numb > 0 {
+                for k in 0..nl {
+                    average[k] += b.ql[k];
+                    average[nl + k] += b.ql_average[k];
+                }
+                numb += 1;
+            }
+        }
+        for x in average[..2 * nl].iter_mut() {
+            *x /= numb as f64;
+        }
+        let line: String = average.iter().enumerate()
+            .map(|(k, x)| if k < 2 * nl { format!("  {:.8}", x) } else { format!("  {}", x) })
+            .collect();
+        o_frame.write_all(format!("{}{}\n", i.frame_idx, line).as_bytes())?;
+        stats.push(&average);
+        Ok(())
+    })?;
+    stats.write(&mut o_frame, &names)?;
+    let mean: Vec<f64> = stats.mean();
+    for k in 0..ICE_NAME.len() {
+        println!("{}: {:.4} molecules per frame", ICE_NAME[k], mean[2 * nl + k]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fcc_and_diamond() {
+        //  12 nearest neighbours of fcc, q4 = 0.19094 and q6 = 0.57452
+        let mut fcc: Vec<(usize, [f64; 3])> = vec![];
+        for a in [-1.0, 1.0] {
+            for b in [-1.0, 1.0] {
+                fcc.extend([(0, [a, b, 0.0]), (0, [a, 0.0, b]), (0, [0.0, a, b])]);
+            }
+        }
+        assert!((order(&local(4, &[fcc.clone()])[0]) - 0.19094).abs() < 1e-5);
+        assert!((order(&local(6, &[fcc])[0]) - 0.57452).abs() < 1e-5);
+
+        //  two atoms of cubic diamond, the bond between them is staggered
+        let a: Vec<(usize, [f64; 3])> =
+            vec![[1.0, 1.0, 1.0], [1.0, -1.0, -1.0], [-1.0, 1.0, -1.0], [-1.0, -1.0, 1.0]].into_iter().map(|v| (0, v)).collect();
+        let b: Vec<(usize, [f64; 3])> = a.iter().map(|(j, v)| (*j, [-v[0], -v[1], -v[2]])).collect();
+        let q3 = local(3, &[a, b]);
+        let c: f64 = dot(&q3[0], &q3[1]) / (dot(&q3[0], &q3[0]) * dot(&q3[1], &q3[1])).sqrt();
+        assert!((c + 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,145 @@
+//! Find the molecules in one frame, considering the periodic boundary condition.
+//!
+//! Each H is given to its nearest heavy atom (O by default) within a cutoff,
+//! so that a frame with H3O+, OH- or solutes does not break the tasks.
+//! The molecules are then classified as water, hydronium, hydroxide or other.
+//! The tasks (cov, hb, ...) use the molecules instead of looking for the
+//! covalent H of each O again.
+//!
+//! A typical use is
+//! ```rust,ignore
+//! let topo = Topology::new(&frame, &["O"], RCUT_COV)?;
+//! for m in topo.molecules.iter().filter(|m| m.species == Species::Water) { ... }
+//! ```
+
+use crate::error::Error;
+use crate::task::neighbour::CellList;
+use crate::task::parallel::map_frames;
+use crate::{Atom, Frame};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+
+/// Default cutoff (Angstrom) of a covalent bond between H and a heavy atom.
+pub const RCUT_COV: f64 = 1.5;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Species {
+    Water,       // H2O
+    Hydronium,   // H3O+
+    Hydroxide,   // OH-
+    Other,       // O without H, O with more than 3 H, or any other heavy atom
+}
+
+impl Species {
+    /// Classify a molecule from the element of its center and its number of H.
+    pub fn classify(center: &str, numb_H: usize) -> Species {
+        match (center, numb_H) {
+            ("O", 2) => Species::Water,
+            ("O", 3) => Species::Hydronium,
+            ("O", 1) => Species::Hydroxide,
+            _ => Species::Other,
+        }
+    }
+}
+
+/// A heavy atom and the H bonded to it.
+pub struct Molecule<'a> {
+    pub center   : &'a Atom,
+    pub hydrogen : Vec<&'a Atom>,   // in the order of the atoms in the frame
+    pub species  : Species,
+}
+
+impl<'a> fmt::Debug for Molecule<'a> {
+    //  print{:?} for struct Molecule
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, " {:?} {}: {:?}", self.species, self.center.type_name, self.center.coordination)?;
+        for h in self.hydrogen.iter() {
+            writeln!(f, "   H: {:?}", h.coordination)?;
+        }
+        Ok(())
+    }
+}
+
+/// The molecules of one frame.
+pub struct Topology<'a> {
+    pub molecules : Vec<Molecule<'a>>,   // one for each heavy atom, in the order of the atoms
+    pub free_H    : Vec<&'a Atom>,       // H without any heavy atom within the cutoff
+}
+
+impl<'a> Topology<'a> {
+    /// Give each H of the frame to the nearest atom of the elements in heavy,
+    /// if it is closer than rcut.
+    ///
+    /// The H are recognised by the type name "H", so it is an Error::Topology
+    /// if no heavy atom gets any H (e.g. lammps types without --typemap).
+    pub fn new(frame: &'a Frame, heavy: &[&str], rcut: f64) -> Result<Topology<'a>, Error> {
+        let mut coord_heavy: Vec<&Atom> = vec![];
+        let mut coord_H: Vec<&Atom> = vec![];
+        for i in frame.atom.iter() {
+            if heavy.contains(&i.type_name.as_str()) {
+                coord_heavy.push(i)
+            } else if i.type_name == "H" {
+                coord_H.push(i)
+            }
+        }
+
+        let list = CellList::new(coord_heavy.iter().map(|x| x.coordination).collect(), &frame.cell, rcut);
+        let mut hydrogen: Vec<Vec<&Atom>> = vec![vec![]; coord_heavy.len()];
+        let mut free_H: Vec<&Atom> = vec![];
+        for atom_h in coord_H.iter() {
+            match list.nearest(atom_h.coordination, 1, 0.0).first() {
+                Some((j, _)) => hydrogen[*j].push(atom_h),
+                None => free_H.push(atom_h),
+            }
+        }
+        if hydrogen.iter().all(|h| h.is_empty()) {
+            return Err(Error::Topology(format!(
+                "no H bonded to {:?} found in frame {}, pass --typemap if the atom types are numbers",
+                heavy, frame.frame_idx
+            )));
+        }
+
+        let molecules: Vec<Molecule> = coord_heavy
+            .iter()
+            .zip(hydrogen)
+            .map(|(&center, hydrogen)| Molecule {
+                center,
+                species: Species::classify(&center.type_name, hydrogen.len()),
+                hydrogen,
+            })
+            .collect();
+        Ok(Topology { molecules, free_H })
+    }
+
+    /// Number of molecules of a species.
+    pub fn count(&self, species: Species) -> usize {
+        self.molecules.iter().filter(|m| m.species == species).count()
+    }
+}
+
+/// Count the species of each frame and write
+/// "frame_idx  #H2O  #H3O+  #OH-  #other  #free_H" for each frame into the output file.
+///
+/// The frames are consumed one batch at a time and computed in parallel.
+pub fn species<I>(system: I, output: &str) -> Result<(), Error>
+where
+    I: Iterator<Item = Result<Frame, Error>>,
+{
+    let mut o = fs::File::create(&output)?;
+    o.write_all("# frame  H2O  H3O+  OH-  other  free_H\n".as_bytes())?;
+    let count = |frame: &Frame| -> Result<[usize; 5], Error> {
+        let topo = Topology::new(frame, &["O"], RCUT_COV)?;
+        Ok([
+            topo.count(Species::Water),
+            topo.count(Species::Hydronium),
+            topo.count(Species::Hydroxide),
+            topo.count(Species::Other),
+            topo.free_H.len(),
+        ])
+    };
+    map_frames(system, count, |i, n| {
+        o.write_all(format!("{}  {}  {}  {}  {}  {}\n", i.frame_idx, n[0], n[1], n[2], n[3], n[4]).as_bytes())?;
+        Ok(())
+    })
+}
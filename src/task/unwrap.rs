@@ -0,0 +1,163 @@
+//! Unwrap the coordinates of a trajectory, i.e. undo the periodic boundary condition.
+//!
+//! - If every atom of every frame has an image flag (lammps `ix iy iz`, or
+//!   `xu yu zu` which are read as wrapped positions with image flags), the
+//!   unwrapped position is r + ix*a + iy*b + iz*c with the cell of that frame.
+//! - Otherwise the displacement between two neighbouring frames is taken as
+//!   the minimum image in the cell of the later frame and accumulated. This
+//!   is correct for NPT trajectories as long as no atom moves more than half
+//!   a box between two frames.
+//!
+//! unwrap_molecules() makes the molecules of the first frame whole before
+//! that, so that each molecule stays whole in the unwrapped trajectory.
+//!
+//! The unwrapped trajectory can be written by the unwrap task, e.g.
+//! ```bash
+//! execfile --in ./a.lammpstrj --infmt lammps/traj --typemap "O H" --task unwrap --taskopt molecule --out ./unwrapped.xyz
+//! ```
+
+use crate::error::Error;
+use crate::task::element::mass;
+use crate::task::get_vector_pbc;
+use crate::task::topology::{Topology, RCUT_COV};
+use crate::Frame;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+
+/// Check that all the frames have the same atoms in the same order.
+fn check_atoms(system: &Vec<Frame>) -> Result<(), Error> {
+    let first: &Frame = match system.first() {
+        Some(frame) => frame,
+        None => return Err(Error::InvalidOption("no frame is selected, please check frameopt".to_string())),
+    };
+    for frame in system.iter() {
+        if frame.atom.len() != first.atom.len()
+            || frame.atom.iter().zip(first.atom.iter()).any(|(a, b)| a.index != b.index)
+        {
+            return Err(Error::Format(format!(
+                "frame {} has different atoms from frame {}, can't unwrap",
+                frame.frame_idx, first.frame_idx
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Unwrap the coordinates of all the frames in place.
+pub fn unwrap(system: &mut Vec<Frame>) -> Result<(), Error> {
+    check_atoms(system)?;
+    let with_image: bool = system.iter().all(|frame| frame.atom.iter().all(|x| x.image.is_some()));
+    if with_image {
+        for frame in system.iter_mut() {
+            let m = frame.cell.matrix;
+            for atom in frame.atom.iter_mut() {
+                let n = atom.image.unwrap();
+                for j in 0..3 {
+                    atom.coordination[j] += n[0] as f64 * m[0][j] + n[1] as f64 * m[1][j] + n[2] as f64 * m[2][j];
+                }
+                atom.image = Some([0; 3]);
+            }
+        }
+        return Ok(());
+    }
+
+    let mut prev: Vec<[f64; 3]> =                           // record the wrapped coordination
+        system[0].atom.iter().map(|x| x.coordination).collect();
+    for k in 1..system.len() {
+        for i in 0..prev.len() {
+            let raw: [f64; 3] = system[k].atom[i].coordination;
+            let dr = get_vector_pbc(prev[i], raw, &system[k].cell);
+            for j in 0..3 {
+                system[k].atom[i].coordination[j] = system[k - 1].atom[i].coordination[j] + dr[j];
+            }
+            prev[i] = raw;
+        }
+    }
+    Ok(())
+}
+
+/// Move the H of each molecule (see task::topology) next to its heavy atom.
+pub fn make_whole(frame: &mut Frame, heavy: &[&str], rcut: f64) -> Result<(), Error> {
+    //  (index, new coordination) of the H
+    let mut moved: HashMap<i32, [f64; 3]> = HashMap::new();
+    let topo = Topology::new(frame, heavy, rcut)?;
+    for molecule in topo.molecules.iter() {
+        let center: [f64; 3] = molecule.center.coordination;
+        for h in molecule.hydrogen.iter() {
+            let dr = get_vector_pbc(center, h.coordination, &frame.cell);
+            moved.insert(h.index, [center[0] + dr[0], center[1] + dr[1], center[2] + dr[2]]);
+        }
+    }
+    for atom in frame.atom.iter_mut() {
+        if let Some(r) = moved.get(&atom.index) {
+            atom.coordination = *r;
+        }
+    }
+    Ok(())
+}
+
+/// Unwrap the coordinates of all the frames in place, keeping the molecules whole.
+///
+/// With image flags the molecules are whole if they are whole in the md code,
+/// otherwise the molecules of the first frame are made whole before unwrapping.
+pub fn unwrap_molecules(system: &mut Vec<Frame>, heavy: &[&str], rcut: f64) -> Result<(), Error> {
+    check_atoms(system)?;
+    let with_image: bool = system.iter().all(|frame| frame.atom.iter().all(|x| x.image.is_some()));
+    if !with_image {
+        make_whole(&mut system[0], heavy, rcut)?;
+    }
+    unwrap(system)
+}
+
+/// Remove the drift of the center of mass of the whole system (which should be
+/// fixed in an md run), i.e. move all the atoms of each frame by COM(0) - COM(t).
+///
+/// The coordinates should be unwrapped.
+pub fn remove_com_drift(system: &mut Vec<Frame>) -> Result<(), Error> {
+    let mut com0: Option<[f64; 3]> = None;
+    for frame in system.iter_mut() {
+        let mut com: [f64; 3] = [0.0; 3];
+        let mut total: f64 = 0.0;
+        for atom in frame.atom.iter() {
+            let m: f64 = mass(&atom.type_name)?;
+            for j in 0..3 {
+                com[j] += m * atom.coordination[j];
+            }
+            total += m;
+        }
+        let com: [f64; 3] = [com[0] / total, com[1] / total, com[2] / total];
+        let com0: [f64; 3] = *com0.get_or_insert(com);
+        for atom in frame.atom.iter_mut() {
+            for j in 0..3 {
+                atom.coordination[j] += com0[j] - com[j];
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write the unwrapped trajectory in xyz format (Cartesian, in Angstrom).
+///
+/// unwrapopt = vec![("molecule")], keep the water molecules (each H with its nearest O) whole.
+pub fn write_unwrapped(system: &mut Vec<Frame>, unwrapopt: &Vec<&str>, output: &str) -> Result<(), Error> {
+    match unwrapopt.get(0).cloned() {
+        None => unwrap(system)?,
+        Some("molecule") => unwrap_molecules(system, &["O"], RCUT_COV)?,
+        Some(x) => return Err(Error::InvalidOption(format!("unknown unwrap option {}, should be molecule or nothing", x))),
+    }
+    let mut o = fs::File::create(output)?;
+    for frame in system.iter() {
+        let m = frame.cell.matrix;
+        o.write_all(format!("{}\n", frame.atom.len()).as_bytes())?;
+        o.write_all(format!(
+            "frame {} Lattice=\"{} {} {} {} {} {} {} {} {}\"\n",
+            frame.frame_idx, m[0][0], m[0][1], m[0][2], m[1][0], m[1][1], m[1][2], m[2][0], m[2][1], m[2][2]
+        ).as_bytes())?;
+        for atom in frame.atom.iter() {
+            let r = atom.coordination;
+            o.write_all(format!("{}  {:.8}  {:.8}  {:.8}\n", atom.type_name, r[0], r[1], r[2]).as_bytes())?;
+        }
+    }
+    Ok(())
+}
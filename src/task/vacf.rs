@@ -0,0 +1,141 @@
+//! The velocity autocorrelation function and the vibrational density of states.
+//!
+//! For each element, averaged over its atoms and the time origins,
+//! - C(t) = <v(0).v(t)> / <v(0).v(0)>, by FFT (see task::fft)
+//! - VDOS g(nu) = 4 int_0^T C(t) w(t) cos(2 pi nu t) dt, normalised so that
+//!   int_0^inf g(nu) dnu = 1, with the Hann window w(t) = cos^2(pi t / 2T)
+//!   up to the largest lag T, which smooths the spectrum
+//!
+//! The velocities are taken from the input file (lammps vx vy vz, QE .vel).
+//! If the file has none (XDATCAR, QE .pos alone), they are derived from the
+//! positions by finite differences (see task::velocity), so the frames should
+//! be close in time (e.g. every md step) for the high frequencies.
+//!
+//! vacfopt = "numb_lag dt", numb_lag is the largest lag in frames and dt is
+//! the time (ps) between two frames used, then nu is in THz.
+//! ```bash
+//! execfile --in ./a.lammpstrj --infmt lammps/traj --typemap "O H" --task vacf --taskopt "1000 0.0005" --out ./vacf.dat
+//! ```
+
+use crate::error::{parse_opt, Error};
+use crate::task::fft::Correlator;
+use crate::task::velocity::ensure_velocities;
+use crate::Frame;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use std::f64::consts::PI;
+use std::fs;
+use std::io::Write;
+
+/// 1 THz in cm^-1.
+const THZ_TO_WAVENUMBER: f64 = 33.35640952;
+
+/// VDOS of the normalised C(t) with the interval dt, as (nu, g(nu)) for nu = 0 .. 1/(2dt).
+pub fn vdos(c: &[f64], dt: f64) -> Vec<(f64, f64)> {
+    let n: usize = c.len();
+    if n < 2 {
+        return vec![];
+    }
+    //  the windowed C(t) made even, C(-t) = C(t), a real cosine transform by FFT
+    let period: usize = 2 * (n - 1);
+    let mut x: Vec<Complex<f64>> = vec![Complex::new(0.0, 0.0); period];
+    for k in 0..n {
+        let w: f64 = (PI * k as f64 / (2.0 * (n - 1) as f64)).cos().powi(2);
+        x[k].re = c[k] * w;
+        if k > 0 && k < n - 1 {
+            x[period - k].re = c[k] * w;
+        }
+    }
+    FftPlanner::new().plan_fft_forward(period).process(&mut x);
+    (0..n).map(|j| (j as f64 / (period as f64 * dt), 2.0 * dt * x[j].re)).collect()
+}
+
+/// Compute the VACF and the VDOS of each element and of all the atoms.
+///
+/// Two files are written, output: "t  C_element...  C_all", output.vdos:
+/// "nu(THz)  wavenumber(cm-1)  g_element...  g_all". All the frames are kept in memory.
+pub fn vacf(system: &mut Vec<Frame>, vacfopt: &Vec<&str>, output: &str) -> Result<(), Error> {
+    let numb_lag: usize = parse_opt(vacfopt, 0, "numb_lag")?;
+    let dt: f64 = parse_opt(vacfopt, 1, "dt")?;
+    if numb_lag < 2 || dt <= 0.0 {
+        return Err(Error::InvalidOption("numb_lag of vacf should be at least 2 and dt positive".to_string()));
+    }
+    let nframe: usize = system.len();
+    if nframe < 2 {
+        return Err(Error::InvalidOption(format!("vacf needs at least 2 frames, {} given", nframe)));
+    }
+    let natom: usize = system[0].atom.len();
+    if system.iter().any(|frame| {
+        frame.atom.len() != natom || frame.atom.iter().zip(system[0].atom.iter()).any(|(a, b)| a.index != b.index)
+    }) {
+        return Err(Error::Format("the atoms change between the frames, can't compute vacf".to_string()));
+    }
+    let derived: bool = ensure_velocities(system, Some(dt))?;
+    let numb_lag: usize = numb_lag.min(nframe);
+
+    //  ------<v(0).v(t)> of each element, in the order of the first frame------
+    let mut elements: Vec<String> = vec![];
+    for atom in system[0].atom.iter() {
+        if !elements.contains(&atom.type_name) {
+            elements.push(atom.type_name.clone());
+        }
+    }
+    let ncol: usize = elements.len() + 1;    // the last one is all the atoms
+    let mut correlation: Vec<Vec<f64>> = vec![vec![0.0; numb_lag]; ncol];
+    let correlator = Correlator::new(nframe);
+    for i in 0..natom {
+        let e: usize = elements.iter().position(|x| *x == system[0].atom[i].type_name).unwrap();
+        for d in 0..3 {
+            let v: Vec<f64> = system.iter().map(|frame| frame.atom[i].velocity.unwrap()[d]).collect();
+            let acf: Vec<f64> = correlator.autocorrelation(&v);
+            for m in 0..numb_lag {
+                let value: f64 = acf[m] / (nframe - m) as f64;
+                correlation[e][m] += value;
+                correlation[ncol - 1][m] += value;
+            }
+        }
+    }
+    for c in correlation.iter_mut() {
+        let c0: f64 = c[0];
+        for x in c.iter_mut() {
+            *x /= c0;
+        }
+    }
+    let spectrum: Vec<Vec<(f64, f64)>> = correlation.iter().map(|c| vdos(c, dt)).collect();
+
+    //  ------write into output files------
+    let mut names: Vec<String> = elements.clone();
+    names.push("all".to_string());
+    let mut o = fs::File::create(output)?;
+    if derived {
+        o.write_all("# velocities derived from the positions by finite differences\n".as_bytes())?;
+    }
+    o.write_all(format!("# t(ps){}\n", names.iter().map(|x| format!("  C_{}", x)).collect::<String>()).as_bytes())?;
+    for m in 0..numb_lag {
+        let line: String = correlation.iter().map(|c| format!("  {:.8}", c[m])).collect();
+        o.write_all(format!("{:.6}{}\n", m as f64 * dt, line).as_bytes())?;
+    }
+    let mut o = fs::File::create(format!("{}.vdos", output))?;
+    o.write_all(format!("# nu(THz)  wavenumber(cm-1){}\n", names.iter().map(|x| format!("  g_{}", x)).collect::<String>()).as_bytes())?;
+    for j in 0..spectrum[0].len() {
+        let nu: f64 = spectrum[0][j].0;
+        let line: String = spectrum.iter().map(|g| format!("  {:.8}", g[j].1)).collect();
+        o.write_all(format!("{:.6}  {:.4}{}\n", nu, nu * THZ_TO_WAVENUMBER, line).as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vdos_of_cosine() {
+        //  C(t) = cos(2 pi nu0 t) with nu0 = 10 THz, the peak is at nu0
+        let dt: f64 = 0.001;
+        let c: Vec<f64> = (0..2001).map(|k| (2.0 * PI * 10.0 * k as f64 * dt).cos()).collect();
+        let g = vdos(&c, dt);
+        let peak = g.iter().cloned().fold((0.0, f64::MIN), |a, b| if b.1 > a.1 { b } else { a });
+        assert!((peak.0 - 10.0).abs() < 0.3);
+    }
+}
@@ -0,0 +1,166 @@
+//! This module computes the non-Gaussian parameter and the self part of the
+//! van Hove correlation function, for the dynamic heterogeneity.
+//!
+//! With the displacement dr(t) = r(t0+t) - r(t0) of an atom (or the center of
+//! mass of a water molecule), averaged over atoms and time origins t0,
+//! - alpha2(t) = d <dr^4> / ((d+2) <dr^2>^2) - 1 for d directions (3 <dr^4> / (5 <dr^2>^2) - 1 in 3D)
+//! - G_s(r, t) = < delta(r - |dr(t)|) > / (4 pi r^2), so that the integral of 4 pi r^2 G_s is 1
+//!
+//! The trajectories are unwrapped as in task::msd.
+//!
+//! Example commands are
+//! ```bash
+//! execfile --in ./a.xdatcar --infmt vasp/xdatcar --task ngp --taskopt "O xyz 1 2000 10 0.01" --out ./ngp.dat
+//! execfile --in ./a.xdatcar --infmt vasp/xdatcar --task vanhove --taskopt "O 100 1000 100 5 250 0.01" --out ./gs.dat
+//! ```
+
+use crate::error::{parse_opt, Error};
+use crate::task::msd::{parse_direction, trajectories};
+use crate::Frame;
+use std::f64::consts::PI;
+use std::fs;
+use std::io::Write;
+
+/// Remove the word "nodrift" from opt, and return whether it is there.
+fn take_nodrift<'a>(opt: &Vec<&'a str>) -> (bool, Vec<&'a str>) {
+    (opt.contains(&"nodrift"), opt.iter().cloned().filter(|x| *x != "nodrift").collect())
+}
+
+/// The lags stepstart, stepstart+dstep, ... < stepstop, checked against the number of frames.
+fn lags(stepstart: i32, stepstop: i32, dstep: i32, nframe: usize) -> Result<Vec<usize>, Error> {
+    if stepstart < 1 || dstep < 1 || stepstop as usize > nframe {
+        return Err(Error::InvalidOption(format!(
+            "need 1 <= stepstart, 1 <= dstep and stepstop <= {} (number of frames)", nframe)));
+    }
+    Ok((stepstart..stepstop).step_by(dstep as usize).map(|x| x as usize).collect())
+}
+
+/// Compute the non-Gaussian parameter alpha2(t), "t  msd  alpha2" is written into the output file.
+///
+/// ngpopt = vec!["elementA", "direction_type", "stepstart", "stepstop", "dstep", ("dt"), ("nodrift")],
+/// the same as task::msd, elementA can be "water".
+pub fn ngp(system: &mut Vec<Frame>, ngpopt: &Vec<&str>, output: &str) -> Result<(), Error> {
+    let (remove_drift, ngpopt) = take_nodrift(ngpopt);
+    let type_name: String = parse_opt(&ngpopt, 0, "elementA")?;
+    let direction: Vec<usize> = parse_direction(&parse_opt::<String>(&ngpopt, 1, "direction_type")?)?;
+    let stepstart: i32 = parse_opt(&ngpopt, 2, "stepstart")?;
+    let stepstop: i32 = parse_opt(&ngpopt, 3, "stepstop")?;
+    let dstep: i32 = parse_opt(&ngpopt, 4, "dstep")?;
+    let dt: Option<f64> = match ngpopt.get(5) {
+        Some(_) => Some(parse_opt(&ngpopt, 5, "dt")?),
+        None => None,
+    };
+    let lags: Vec<usize> = lags(stepstart, stepstop, dstep, system.len())?;
+    let centers: Vec<Vec<[f64; 3]>> = trajectories(system, &type_name, remove_drift)?;
+    let nframe: usize = system.len();
+    let d: f64 = direction.len() as f64;
+
+    let mut o = fs::File::create(output)?;
+    for dk in lags {
+        let mut r2: f64 = 0.0;
+        let mut r4: f64 = 0.0;
+        let mut count: f64 = 0.0;
+        for center in centers.iter() {
+            for k in 0..nframe - dk {
+                let dr2: f64 = direction.iter().map(|&j| (center[k + dk][j] - center[k][j]).powi(2)).sum();
+                r2 += dr2;
+                r4 += dr2 * dr2;
+                count += 1.0;
+            }
+        }
+        let (r2, r4) = (r2 / count, r4 / count);
+        let alpha2: f64 = d * r4 / ((d + 2.0) * r2 * r2) - 1.0;
+        let time: String = match dt {
+            Some(dt) => format!("{:.4}", dk as f64 * dt),
+            None => format!("{}", dk),
+        };
+        o.write_all(format!("{}  {:.8}  {:.8}\n", time, r2, alpha2).as_bytes())?;
+    }
+    Ok(())
+}
+
+/// The bin of a displacement d < rmax, d / dr may round up to numb_bins just below rmax.
+fn bin(d: f64, dr: f64, numb_bins: usize) -> usize {
+    ((d / dr) as usize).min(numb_bins - 1)
+}
+
+/// Compute the self part of the van Hove function G_s(r, t) in 3D, the output
+/// file has a column of r (bin centers) and a column of G_s for each lag.
+///
+/// vhopt = vec!["elementA", "stepstart", "stepstop", "dstep", "rmax", "numb_bins", ("dt"), ("nodrift")],
+/// elementA can be "water".
+pub fn van_hove(system: &mut Vec<Frame>, vhopt: &Vec<&str>, output: &str) -> Result<(), Error> {
+    let (remove_drift, vhopt) = take_nodrift(vhopt);
+    let type_name: String = parse_opt(&vhopt, 0, "elementA")?;
+    let stepstart: i32 = parse_opt(&vhopt, 1, "stepstart")?;
+    let stepstop: i32 = parse_opt(&vhopt, 2, "stepstop")?;
+    let dstep: i32 = parse_opt(&vhopt, 3, "dstep")?;
+    let rmax: f64 = parse_opt(&vhopt, 4, "rmax")?;
+    let numb_bins: usize = parse_opt(&vhopt, 5, "numb_bins")?;
+    let dt: Option<f64> = match vhopt.get(6) {
+        Some(_) => Some(parse_opt(&vhopt, 6, "dt")?),
+        None => None,
+    };
+    if rmax <= 0.0 || numb_bins == 0 {
+        return Err(Error::InvalidOption("rmax and numb_bins of vanhove should be positive".to_string()));
+    }
+    let lags: Vec<usize> = lags(stepstart, stepstop, dstep, system.len())?;
+    let centers: Vec<Vec<[f64; 3]>> = trajectories(system, &type_name, remove_drift)?;
+    let nframe: usize = system.len();
+    let dr: f64 = rmax / numb_bins as f64;
+
+    //  gs[lag][bin]
+    let mut gs: Vec<Vec<f64>> = vec![];
+    for &dk in lags.iter() {
+        let mut count: Vec<f64> = vec![0.0; numb_bins];
+        let mut numb_sample: f64 = 0.0;
+        for center in centers.iter() {
+            for k in 0..nframe - dk {
+                let d: f64 = (0..3).map(|j| (center[k + dk][j] - center[k][j]).powi(2)).sum::<f64>().sqrt();
+                numb_sample += 1.0;
+                if d < rmax {
+                    count[bin(d, dr, numb_bins)] += 1.0;
+                }
+            }
+        }
+        //  exact volume of each shell
+        for (i, c) in count.iter_mut().enumerate() {
+            let shell: f64 = 4.0 / 3.0 * PI * (((i + 1) as f64 * dr).powi(3) - (i as f64 * dr).powi(3));
+            *c /= numb_sample * shell;
+        }
+        gs.push(count);
+    }
+
+    let mut o = fs::File::create(output)?;
+    let mut header: String = "# r".to_string();
+    for &dk in lags.iter() {
+        header += &match dt {
+            Some(dt) => format!("  t={:.4}", dk as f64 * dt),
+            None => format!("  t={}", dk),
+        };
+    }
+    o.write_all((header + "\n").as_bytes())?;
+    for i in 0..numb_bins {
+        let mut line: String = format!("{:.4}", (i as f64 + 0.5) * dr);
+        for g in gs.iter() {
+            line += &format!("  {:.8}", g[i]);
+        }
+        o.write_all((line + "\n").as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bin_just_below_rmax() {
+        let (rmax, numb_bins): (f64, usize) = (3.3, 398);
+        let dr: f64 = rmax / numb_bins as f64;
+        let d: f64 = 3.2999999999999994;    // the largest f64 below 3.3
+        assert!(d < rmax && (d / dr) as usize == numb_bins);
+        assert_eq!(bin(d, dr, numb_bins), numb_bins - 1);
+        assert_eq!(bin(0.5 * dr, dr, numb_bins), 0);
+    }
+}
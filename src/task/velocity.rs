@@ -0,0 +1,101 @@
+//! Velocities of a trajectory with only positions (XDATCAR, QE .pos alone).
+//!
+//! The velocities are the central differences of the unwrapped positions
+//! (see task::unwrap), v(t) = (r(t+dt) - r(t-dt)) / 2dt, and the forward or
+//! backward difference for the first and the last frame, with the time dt
+//! (ps) between two frames given by the user, so they are in Angstrom/ps.
+//! The frames are marked with velocity_derived, and the tasks using the
+//! velocities (vacf, temperature) say so in their output.
+//!
+//! The error of the central difference grows with dt, the frames should be
+//! close in time (every md step or a few) for the fast motions (O-H stretch).
+//!
+//! The temperature task computes the kinetic temperature of each frame,
+//! T = sum m v^2 / (3 N k_B), without the correction of the constraints and
+//! of the fixed center of mass, the velocities should be in Angstrom/ps
+//! (lammps metal units, QE, or derived). temperatureopt is "[dt]", dt is
+//! only needed without velocities in the input file.
+//! ```bash
+//! execfile --in ./a.xdatcar --infmt vasp/xdatcar --task temperature --taskopt "0.0005" --out ./temperature.dat
+//! ```
+
+use crate::error::{parse_opt, Error};
+use crate::task::element::mass;
+use crate::task::stats::BlockAverage;
+use crate::task::unwrap::unwrap;
+use crate::Frame;
+use std::fs;
+use std::io::Write;
+
+/// Gas constant in J/(mol K), as the masses are in g/mol.
+const GAS_CONSTANT: f64 = 8.314462618;
+
+/// Set the velocities of all the atoms from the positions and mark the frames,
+/// dt is the time (ps) between two frames. The coordinates are unwrapped in place.
+pub fn derive_velocities(system: &mut Vec<Frame>, dt: f64) -> Result<(), Error> {
+    if system.len() < 2 {
+        return Err(Error::InvalidOption("need at least 2 frames to derive the velocities".to_string()));
+    }
+    if dt <= 0.0 {
+        return Err(Error::InvalidOption(format!("the time between two frames should be positive, not {}", dt)));
+    }
+    unwrap(system)?;
+    let nframe: usize = system.len();
+    for k in 0..nframe {
+        let before: usize = if k > 0 { k - 1 } else { 0 };
+        let after: usize = if k + 1 < nframe { k + 1 } else { k };
+        let time: f64 = (after - before) as f64 * dt;
+        for i in 0..system[k].atom.len() {
+            let a = system[before].atom[i].coordination;
+            let b = system[after].atom[i].coordination;
+            system[k].atom[i].velocity = Some([(b[0] - a[0]) / time, (b[1] - a[1]) / time, (b[2] - a[2]) / time]);
+        }
+        system[k].velocity_derived = true;
+    }
+    Ok(())
+}
+
+/// Derive the velocities (see derive_velocities()) if any atom has no velocity
+/// from the input file, dt is needed only then. Return whether they are derived.
+pub fn ensure_velocities(system: &mut Vec<Frame>, dt: Option<f64>) -> Result<bool, Error> {
+    if system.iter().all(|frame| frame.atom.iter().all(|x| x.velocity.is_some())) {
+        return Ok(system.iter().any(|frame| frame.velocity_derived));
+    }
+    match dt {
+        Some(dt) => {
+            println!("No velocity in the input file, use the finite differences of the positions");
+            derive_velocities(system, dt)?;
+            Ok(true)
+        }
+        None => Err(Error::InvalidOption(
+            "no velocity in the input file, give the time (ps) between two frames to derive them".to_string())),
+    }
+}
+
+/// Write "frame  T(K)" of each frame, followed by the average and its error (see task::stats).
+pub fn temperature(system: &mut Vec<Frame>, temperatureopt: &Vec<&str>, output: &str) -> Result<(), Error> {
+    let dt: Option<f64> = match temperatureopt.is_empty() {
+        true => None,
+        false => Some(parse_opt(temperatureopt, 0, "dt")?),
+    };
+    let derived: bool = ensure_velocities(system, dt)?;
+    let mut o = fs::File::create(output)?;
+    if derived {
+        o.write_all("# velocities derived from the positions by finite differences\n".as_bytes())?;
+    }
+    o.write_all("# frame  T(K)\n".as_bytes())?;
+    let mut stats = BlockAverage::new(1);
+    for frame in system.iter() {
+        let mut twice_kinetic: f64 = 0.0;       // sum m v^2 in g/mol A^2/ps^2 = 10 J/mol
+        for atom in frame.atom.iter() {
+            let v = atom.velocity.unwrap();
+            twice_kinetic += mass(&atom.type_name)? * (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]);
+        }
+        let t: f64 = 10.0 * twice_kinetic / (3.0 * frame.atom.len() as f64 * GAS_CONSTANT);
+        o.write_all(format!("{}  {:.4}\n", frame.frame_idx, t).as_bytes())?;
+        stats.push(&[t]);
+    }
+    println!("Average temperature is {:.4} +- {:.4} K", stats.mean()[0], stats.error().1[0]);
+    stats.write(&mut o, &["T".to_string()])?;
+    Ok(())
+}